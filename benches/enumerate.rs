@@ -0,0 +1,78 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashSet},
+    hash::{Hash, Hasher},
+};
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use workload_iter::Workload;
+
+/// Templates of growing size with four instances of the hole, plugged with 8 pegs, so
+/// every template expands into 4096 terms.
+fn workloads() -> Vec<(usize, Workload)> {
+    [1, 4, 16]
+        .into_iter()
+        .map(|width| {
+            let src = format!(
+                "plug({{(+ (* ?A ?A) (- ?A ?A) {})}}, A, {{0 1 2 3 x y z w}})",
+                "(f (g a b) (h c d))".repeat(width)
+            );
+            (width, Workload::parse(&src).unwrap())
+        })
+        .collect()
+}
+
+fn enumerate(c: &mut Criterion) {
+    let mut group = c.benchmark_group("enumerate");
+    for (width, wkld) in workloads() {
+        group.throughput(Throughput::Elements(wkld.count().unwrap() as u64));
+        group.bench_with_input(BenchmarkId::new("sexp", width), &wkld, |b, wkld| {
+            b.iter(|| black_box(wkld.clone()).into_iter().count())
+        });
+        group.bench_with_input(BenchmarkId::new("term", width), &wkld, |b, wkld| {
+            b.iter(|| black_box(wkld.clone()).into_terms().count())
+        });
+    }
+    group.finish();
+}
+
+fn dedup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dedup");
+    for (width, wkld) in workloads() {
+        group.throughput(Throughput::Elements(wkld.count().unwrap() as u64));
+        group.bench_with_input(BenchmarkId::new("sexp", width), &wkld, |b, wkld| {
+            b.iter(|| wkld.clone().into_iter().collect::<HashSet<_>>().len())
+        });
+        group.bench_with_input(BenchmarkId::new("term", width), &wkld, |b, wkld| {
+            b.iter(|| wkld.clone().into_terms().collect::<HashSet<_>>().len())
+        });
+    }
+    group.finish();
+}
+
+/// Hash every term, which doesn't need to own it.
+fn hash(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hash");
+    for (width, wkld) in workloads() {
+        group.throughput(Throughput::Elements(wkld.count().unwrap() as u64));
+        group.bench_with_input(BenchmarkId::new("owned", width), &wkld, |b, wkld| {
+            b.iter(|| {
+                let mut hasher = DefaultHasher::new();
+                wkld.clone()
+                    .into_iter()
+                    .for_each(|sexp| sexp.hash(&mut hasher));
+                hasher.finish()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("lent", width), &wkld, |b, wkld| {
+            b.iter(|| {
+                let mut hasher = DefaultHasher::new();
+                wkld.clone().for_each_ref(|sexp| sexp.hash(&mut hasher));
+                hasher.finish()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, enumerate, dedup, hash);
+criterion_main!(benches);
@@ -0,0 +1,475 @@
+use std::{fmt::Display, str::FromStr};
+
+use crate::{parse::ParseError, workload::SharedPegs, Filter, Sexp, SexpSubstIter, Workload};
+
+/// A peg iterator that knows how many pegs it has produced.
+struct Counted {
+    iter: Box<dyn Iterator<Item = Sexp>>,
+    taken: usize,
+}
+
+impl Iterator for Counted {
+    type Item = Sexp;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let peg = self.iter.next()?;
+        self.taken += 1;
+        Some(peg)
+    }
+}
+
+type Spawn = Box<dyn Fn() -> Counted>;
+
+/// A depth-first plug whose position in the substitution tree can be recorded.
+struct PlugNode {
+    templates: Node,
+    hole: String,
+    pegs: SharedPegs,
+    rightmost_first: bool,
+    current: Option<SexpSubstIter<Counted, Spawn>>,
+}
+
+impl PlugNode {
+    fn spawn(&self) -> Spawn {
+        let pegs = self.pegs.clone();
+        Box::new(move || Counted {
+            iter: pegs.iter(),
+            taken: 0,
+        })
+    }
+
+    fn subst(&self, template: Sexp) -> SexpSubstIter<Counted, Spawn> {
+        let iter = SexpSubstIter::new(template, self.hole.clone(), self.spawn());
+        if self.rightmost_first {
+            iter.rightmost_first()
+        } else {
+            iter
+        }
+    }
+
+    /// `(template n_1 .. n_k)`: the template being plugged, and the number of pegs
+    /// taken by each frame of the traversal, from the root down. Empty if there is no
+    /// template being plugged.
+    fn frames(&self) -> Sexp {
+        let Some(iter) = &self.current else {
+            return Sexp::List(vec![]);
+        };
+        Sexp::List(
+            iter.template()
+                .cloned()
+                .into_iter()
+                .chain(iter.pegs().map(|pegs| number(pegs.taken)))
+                .collect(),
+        )
+    }
+
+    /// Rebuild the frames of the traversal from the output of [`PlugNode::frames`], by
+    /// taking the same number of pegs in each frame and filling it in with the last of
+    /// them.
+    fn resume_frames(&self, frames: &Sexp) -> Option<Option<SexpSubstIter<Counted, Spawn>>> {
+        let Some((template, counts)) = list(frames)?.split_first() else {
+            return Some(None);
+        };
+        let spawn = self.spawn();
+        let mut stack = vec![];
+        for taken in counts {
+            let mut pegs = spawn();
+            let mut last = None;
+            for _ in 0..parse_number(taken)? {
+                last = Some(pegs.next()?);
+            }
+            stack.push((pegs, last));
+        }
+        SexpSubstIter::from_frames(
+            template.clone(),
+            self.hole.clone(),
+            self.rightmost_first,
+            spawn,
+            stack,
+        )
+        .map(Some)
+    }
+}
+
+impl Iterator for PlugNode {
+    type Item = Sexp;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(sexp) = self.current.as_mut().and_then(Iterator::next) {
+                return Some(sexp);
+            }
+            let template = self.templates.next()?;
+            self.current = Some(self.subst(template));
+        }
+    }
+}
+
+/// The state of the enumeration of one node of a workload. Every variant mirrors how
+/// [`Workload::into_iter`] enumerates the corresponding workload, so that it produces
+/// the same terms in the same order.
+enum Node {
+    Set(Vec<Sexp>, usize),
+    /// Anything whose state isn't recorded, i.e. streams, dedups and the plugs that
+    /// aren't depth-first plugs of a single independent hole, along with the number of
+    /// terms produced so far. These are resumed by skipping that many terms.
+    Skip(Box<dyn Iterator<Item = Sexp>>, usize),
+    Plug(Box<PlugNode>),
+    Filter(Box<Node>, Filter),
+    /// The parts, the index of the current one, and its state once it has started.
+    Append(Vec<Workload>, usize, Option<Box<Node>>),
+    /// The parts that haven't run out yet, with their original index, and the index
+    /// of the one whose turn it is.
+    Interleave(Vec<(usize, Node)>, usize),
+}
+
+impl Node {
+    fn start(wkld: Workload) -> Self {
+        let wkld = match wkld.into_lexicographic_plug() {
+            Ok((templates, hole, pegs, rightmost_first)) => {
+                return Node::Plug(Box::new(PlugNode {
+                    templates: Node::start(templates),
+                    hole,
+                    pegs: SharedPegs::new(pegs),
+                    rightmost_first,
+                    current: None,
+                }));
+            }
+            Err(wkld) => wkld,
+        };
+        match wkld {
+            Workload::Set(v) => Node::Set(v, 0),
+            Workload::Filter(wkld, filter) => Node::Filter(Box::new(Node::start(*wkld)), filter),
+            Workload::Append(wklds) => Node::Append(wklds, 0, None),
+            Workload::Interleave(wklds) => {
+                Node::Interleave(wklds.into_iter().map(Node::start).enumerate().collect(), 0)
+            }
+            wkld @ (Workload::Stream(_)
+            | Workload::Plug(..)
+            | Workload::PlugMany(..)
+            | Workload::Dedup(..)) => Node::Skip(wkld.into_iter(), 0),
+        }
+    }
+
+    fn checkpoint(&self) -> Sexp {
+        let tagged = |tag: &str, rest: Vec<Sexp>| {
+            Sexp::List(
+                std::iter::once(Sexp::Atom(tag.to_string()))
+                    .chain(rest)
+                    .collect(),
+            )
+        };
+        match self {
+            Node::Set(_, pos) => tagged("set", vec![number(*pos)]),
+            Node::Skip(_, n) => tagged("skip", vec![number(*n)]),
+            Node::Plug(plug) => tagged("plug", vec![plug.templates.checkpoint(), plug.frames()]),
+            Node::Filter(node, _) => tagged("filter", vec![node.checkpoint()]),
+            Node::Append(_, index, current) => tagged(
+                "append",
+                std::iter::once(number(*index))
+                    .chain(current.iter().map(|node| node.checkpoint()))
+                    .collect(),
+            ),
+            Node::Interleave(live, turn) => tagged(
+                "interleave",
+                std::iter::once(number(*turn))
+                    .chain(
+                        live.iter()
+                            .map(|(i, node)| Sexp::List(vec![number(*i), node.checkpoint()])),
+                    )
+                    .collect(),
+            ),
+        }
+    }
+
+    /// The state recorded by [`Node::checkpoint`], or `None` if it doesn't fit `wkld`.
+    fn resume(wkld: Workload, checkpoint: &Sexp) -> Option<Self> {
+        let (tag, rest) = list(checkpoint)?.split_first()?;
+        let Sexp::Atom(tag) = tag else {
+            return None;
+        };
+        let wkld = match wkld.into_lexicographic_plug() {
+            Ok((templates, hole, pegs, rightmost_first)) => {
+                let ("plug", [node, frames]) = (tag.as_str(), rest) else {
+                    return None;
+                };
+                let mut plug = PlugNode {
+                    templates: Node::resume(templates, node)?,
+                    hole,
+                    pegs: SharedPegs::new(pegs),
+                    rightmost_first,
+                    current: None,
+                };
+                plug.current = plug.resume_frames(frames)?;
+                return Some(Node::Plug(Box::new(plug)));
+            }
+            Err(wkld) => wkld,
+        };
+        match (wkld, tag.as_str(), rest) {
+            (Workload::Set(v), "set", [pos]) => {
+                let pos = parse_number(pos)?;
+                (pos <= v.len()).then_some(Node::Set(v, pos))
+            }
+            // the lexicographic plugs were handled above
+            (
+                wkld @ (Workload::Stream(_)
+                | Workload::Plug(..)
+                | Workload::PlugMany(..)
+                | Workload::Dedup(..)),
+                "skip",
+                [n],
+            ) => {
+                let n = parse_number(n)?;
+                let mut iter = wkld.into_iter();
+                if n > 0 {
+                    iter.nth(n - 1)?;
+                }
+                Some(Node::Skip(iter, n))
+            }
+            (Workload::Filter(wkld, filter), "filter", [node]) => {
+                Some(Node::Filter(Box::new(Node::resume(*wkld, node)?), filter))
+            }
+            (Workload::Append(wklds), "append", [index, current @ ..]) => {
+                let index = parse_number(index)?;
+                let current = match current {
+                    [] if index <= wklds.len() => None,
+                    [node] => Some(Box::new(Node::resume(wklds.get(index)?.clone(), node)?)),
+                    _ => return None,
+                };
+                Some(Node::Append(wklds, index, current))
+            }
+            (Workload::Interleave(wklds), "interleave", [turn, live @ ..]) => {
+                let mut nodes = vec![];
+                for entry in live {
+                    let [i, node] = list(entry)? else {
+                        return None;
+                    };
+                    let i = parse_number(i)?;
+                    // the parts that are left keep their relative order
+                    if nodes.last().is_some_and(|&(last, _)| last >= i) {
+                        return None;
+                    }
+                    nodes.push((i, Node::resume(wklds.get(i)?.clone(), node)?));
+                }
+                Some(Node::Interleave(nodes, parse_number(turn)?))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Iterator for Node {
+    type Item = Sexp;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Node::Set(v, pos) => {
+                let sexp = v.get(*pos)?.clone();
+                *pos += 1;
+                Some(sexp)
+            }
+            Node::Skip(iter, n) => {
+                let sexp = iter.next()?;
+                *n += 1;
+                Some(sexp)
+            }
+            Node::Plug(plug) => plug.next(),
+            Node::Filter(node, filter) => node.find(|sexp| filter.test(sexp)),
+            Node::Append(wklds, index, current) => {
+                while let Some(wkld) = wklds.get(*index) {
+                    let node = current.get_or_insert_with(|| Box::new(Node::start(wkld.clone())));
+                    if let Some(sexp) = node.next() {
+                        return Some(sexp);
+                    }
+                    *index += 1;
+                    *current = None;
+                }
+                None
+            }
+            Node::Interleave(live, turn) => {
+                while !live.is_empty() {
+                    *turn %= live.len();
+                    match live[*turn].1.next() {
+                        Some(sexp) => {
+                            *turn += 1;
+                            return Some(sexp);
+                        }
+                        None => {
+                            live.remove(*turn);
+                        }
+                    }
+                }
+                None
+            }
+        }
+    }
+}
+
+fn number(n: usize) -> Sexp {
+    Sexp::Atom(n.to_string())
+}
+
+fn parse_number(sexp: &Sexp) -> Option<usize> {
+    match sexp {
+        Sexp::Atom(a) => a.parse().ok(),
+        Sexp::Hole(_) | Sexp::List(_) => None,
+    }
+}
+
+fn list(sexp: &Sexp) -> Option<&[Sexp]> {
+    match sexp {
+        Sexp::Atom(_) | Sexp::Hole(_) => None,
+        Sexp::List(list) => Some(list),
+    }
+}
+
+/// An enumeration of a workload that can be saved with [`Cursor::checkpoint`] and
+/// picked up again with [`Workload::resume`]. It produces the same terms in the same
+/// order as iterating the workload.
+pub struct Cursor(Node);
+
+impl Cursor {
+    /// The state of the enumeration, i.e. which terms have been produced so far.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint(self.0.checkpoint())
+    }
+}
+
+impl Iterator for Cursor {
+    type Item = Sexp;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+}
+
+/// The state of a [`Cursor`], which is printed and parsed as an s-expression that
+/// mirrors the structure of the workload. For sets and depth-first plugs it records
+/// positions: for a plug, the template being filled in and the number of pegs taken
+/// by every frame of its [`SexpSubstIter`]. Streams, dedups and other kinds of plugs
+/// are recorded by the number of terms they have produced, and are resumed by
+/// enumerating and skipping as many terms.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Checkpoint(Sexp);
+
+impl Display for Checkpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for Checkpoint {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Sexp::parse(s).map(Checkpoint)
+    }
+}
+
+impl Workload {
+    /// Enumerate the workload like [`Workload::into_iter`], but in a way that can be
+    /// saved and resumed, see [`Cursor::checkpoint`].
+    pub fn cursor(self) -> Cursor {
+        Cursor(Node::start(self))
+    }
+
+    /// Continue an enumeration of this workload where the cursor that produced
+    /// `checkpoint` left off. Returns `None` if the checkpoint doesn't belong to a
+    /// workload with this structure.
+    ///
+    /// The workload has to be the same one that the checkpoint was taken from: only the
+    /// structure is checked, so resuming with different sets or pegs continues from
+    /// the same positions in those instead.
+    pub fn resume(self, checkpoint: &Checkpoint) -> Option<Cursor> {
+        Node::resume(self, &checkpoint.0).map(Cursor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Workloads with every kind of node: sets, depth-first and reverse-lex plugs (some
+    /// nested, with pegs that contain the outer hole), and skips for dedups and plugs
+    /// that aren't recorded by position, under filters, appends and interleavings.
+    const WORKLOADS: &[&str] = &[
+        "{a b c}",
+        "plug({(+ ?A ?A) x}, A, {0 1 2})",
+        "plug(plug({(f ?A ?B) (g ?B)}, A, {?B (h ?B)}, reverse-lex), B, {0 1})",
+        "dedup(plug({(f ?A) (f ?B)}, A, {0 1}))",
+        "plug({(+ ?A ?A)}, A, {0 1 2}, breadth-first)",
+        "plug({(+ ?A ?A)}, A, {0 1 2}, uniform)",
+        "filter(plug({(+ ?A ?A)}, A, {0 1 2}), contains 1)",
+        "append({a}, plug({(f ?A ?A)}, A, {0 1}), {}, dedup({b b c}))",
+        "interleave({a b c d}, plug({(f ?A)}, A, {0 1}), {x})",
+        "plug(interleave({(f ?A)}, append({(g ?A ?A)}, {h})), A, {0 1})",
+    ];
+
+    #[test]
+    fn cursor_is_into_iter() {
+        for src in WORKLOADS {
+            let wkld = Workload::parse(src).unwrap();
+            let terms: Vec<Sexp> = wkld.clone().into_iter().collect();
+            assert_eq!(wkld.cursor().collect::<Vec<_>>(), terms, "{src}");
+        }
+    }
+
+    #[test]
+    fn resume_after_round_trip() {
+        for src in WORKLOADS {
+            let wkld = Workload::parse(src).unwrap();
+            let terms: Vec<Sexp> = wkld.clone().into_iter().collect();
+            for taken in 0..=terms.len() {
+                let mut cursor = wkld.clone().cursor();
+                cursor.by_ref().take(taken).for_each(drop);
+                let checkpoint: Checkpoint = cursor.checkpoint().to_string().parse().unwrap();
+                assert_eq!(checkpoint, cursor.checkpoint(), "{src} {taken}");
+                let resumed = wkld.clone().resume(&checkpoint).unwrap();
+                assert_eq!(resumed.collect::<Vec<_>>(), terms[taken..], "{src} {taken}");
+            }
+        }
+    }
+
+    #[test]
+    fn mismatched_checkpoints() {
+        let resume = |src: &str, checkpoint: &str| {
+            Workload::parse(src)
+                .unwrap()
+                .resume(&checkpoint.parse().unwrap())
+                .is_some()
+        };
+        let checkpoint = |src: &str| Workload::parse(src).unwrap().cursor().checkpoint();
+
+        // a checkpoint from a workload with another structure, where skips only record
+        // a number of terms, whatever kind of workload they skip
+        let skips = |src: &str| checkpoint(src).to_string().starts_with("(skip");
+        for (i, a) in WORKLOADS.iter().enumerate() {
+            for (j, b) in WORKLOADS.iter().enumerate() {
+                let fits = Workload::parse(b).unwrap().resume(&checkpoint(a)).is_some();
+                assert_eq!(fits, i == j || skips(a) && skips(b), "{a} {b}");
+            }
+        }
+
+        assert!(resume("{a b c}", "(set 3)"));
+        assert!(!resume("{a b c}", "(set 4)"));
+        assert!(!resume("{a b c}", "(set x)"));
+        assert!(!resume("{a b c}", "(set 0 0)"));
+        assert!(!resume("{a b c}", "set"));
+        assert!(!resume("dedup({a b})", "(skip 3)"));
+        let plug = "plug({(f ?A)}, A, {0 1})";
+        assert!(resume(plug, "(plug (set 1) ((f ?A) 1))"));
+        assert!(!resume(plug, "(plug (set 1) ((f ?A) 3))"));
+        // more frames than the template has instances of the hole
+        assert!(!resume(plug, "(plug (set 1) ((f ?A) 1 1))"));
+        assert!(!resume("append({a}, {b})", "(append 3)"));
+        assert!(!resume("append({a}, {b})", "(append 2 (set 0))"));
+        assert!(!resume(
+            "interleave({a}, {b})",
+            "(interleave 0 (1 (set 0)) (0 (set 0)))"
+        ));
+        assert!(!resume(
+            "interleave({a}, {b})",
+            "(interleave 0 (2 (set 0)))"
+        ));
+    }
+}
@@ -0,0 +1,216 @@
+use std::fmt::Display;
+
+use crate::{Atom, PlugMode, Sexp, Workload};
+
+/// Why [`Workload::count`] couldn't produce a number.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum CountError {
+    /// The workload contains a filter or a stream, whose size can only be found by
+    /// enumerating it (if at all).
+    Unknown,
+    /// The workload has more than `u128::MAX` terms.
+    Overflow,
+}
+
+impl Display for CountError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CountError::Unknown => write!(f, "the number of terms can't be computed up front"),
+            CountError::Overflow => write!(f, "the number of terms doesn't fit in a u128"),
+        }
+    }
+}
+
+impl std::error::Error for CountError {}
+
+/// Weights for the holes of enclosing plugs, innermost last.
+///
+/// A term of a workload that is plugged further up expands into one term for every way
+/// of filling in the holes of those plugs. So while counting a template workload, a
+/// term counts as the product of the weights of the holes it contains, where the
+/// weight of a hole is the number of ways of filling in one instance of it. The
+/// instances of a [`PlugMode::Uniform`] hole are filled in together, so its weight only
+/// counts once, and the instances of a [`PlugMode::Commutative`] hole among the
+/// arguments of a commutative operator count as a multiset.
+pub(crate) type Weights<'a, A = String> = Vec<(&'a A, u128, &'a PlugMode<A>)>;
+
+pub(crate) fn checked_add(a: u128, b: u128) -> Result<u128, CountError> {
+    a.checked_add(b).ok_or(CountError::Overflow)
+}
+
+pub(crate) fn checked_mul(a: u128, b: u128) -> Result<u128, CountError> {
+    a.checked_mul(b).ok_or(CountError::Overflow)
+}
+
+/// The number of multisets of size `k` with elements from a set of size `n`.
+fn multichoose(n: u128, k: usize) -> Result<u128, CountError> {
+    // every prefix of the product is itself a binomial coefficient, so the division
+    // is exact
+    (1..=k as u128).try_fold(
+        1,
+        |acc, i| Ok(checked_mul(acc, checked_add(n, i - 1)?)? / i),
+    )
+}
+
+/// The weight of `atom`, if it is one of the holes in `weights`.
+pub(crate) fn hole_weight<A: Atom>(weights: &Weights<A>, atom: &A) -> Option<u128> {
+    weights
+        .iter()
+        .rev()
+        .find(|(hole, ..)| *hole == atom)
+        .map(|&(_, w, _)| w)
+}
+
+impl<A: Atom> Sexp<A> {
+    /// The number of terms this expands into when the holes in `weights` are filled in.
+    pub(crate) fn weight(&self, weights: &Weights<A>) -> Result<u128, CountError> {
+        let mut weight = self.instance_weight(weights)?;
+        for (i, &(hole, w, mode)) in weights.iter().enumerate() {
+            let shadowed = weights[i + 1..].iter().any(|&(h, ..)| h == hole);
+            if *mode == PlugMode::Uniform && !shadowed && self.occurrences(hole) > 0 {
+                weight = checked_mul(weight, w)?;
+            }
+        }
+        Ok(weight)
+    }
+
+    /// The product of the weights of the instances of holes that aren't uniform.
+    fn instance_weight(&self, weights: &Weights<A>) -> Result<u128, CountError> {
+        let hole = |atom: &A| weights.iter().rev().find(|(hole, ..)| *hole == atom);
+        match self {
+            Sexp::Hole(h) => Ok(match hole(h) {
+                Some(&(_, w, PlugMode::Independent | PlugMode::Commutative(_))) => w,
+                _ => 1,
+            }),
+            Sexp::Atom(_) => Ok(1),
+            Sexp::List(list) => {
+                // the number of instances of every commutative hole among the arguments
+                let mut multisets: Vec<(&A, u128, usize)> = vec![];
+                let mut weight = 1;
+                for s in list {
+                    if let (Some(Sexp::Atom(op)), Sexp::Hole(a)) = (list.first(), s) {
+                        if let Some(&(h, w, PlugMode::Commutative(ops))) = hole(a) {
+                            if ops.contains(op) {
+                                match multisets.iter_mut().find(|(hole, ..)| *hole == h) {
+                                    Some((_, _, k)) => *k += 1,
+                                    None => multisets.push((h, w, 1)),
+                                }
+                                continue;
+                            }
+                        }
+                    }
+                    weight = checked_mul(weight, s.instance_weight(weights)?)?;
+                }
+                multisets.into_iter().try_fold(weight, |acc, (_, w, k)| {
+                    checked_mul(acc, multichoose(w, k)?)
+                })
+            }
+        }
+    }
+}
+
+impl<A: Atom> Workload<A> {
+    /// The number of terms that iterating this workload produces, computed from the
+    /// structure of the workload rather than by enumerating it.
+    ///
+    /// Sets count their elements, appends add up their parts, and a plug counts every
+    /// template as the number of pegs to the power of the number of instances of the
+    /// hole in it (or the product of that over the holes of a [`Workload::PlugMany`]),
+    /// or just the number of pegs if the hole is [`PlugMode::Uniform`]. For a
+    /// [`PlugMode::Commutative`] hole, `k` instances among the arguments of a
+    /// commutative operator count as the number of multisets of `k` pegs. A template
+    /// without the hole counts once, unless there are no pegs at all. Filters, dedups and
+    /// streams make the count [`CountError::Unknown`], and so do pegs of a uniform or
+    /// commutative hole that contain holes of enclosing plugs, and pegs of a
+    /// lexicographic plug that contain its own hole.
+    pub fn count(&self) -> Result<u128, CountError> {
+        self.weighted_count(&vec![])
+    }
+
+    /// The sum of the weights of the terms of this workload.
+    pub(crate) fn weighted_count(&self, weights: &Weights<A>) -> Result<u128, CountError> {
+        match self {
+            Workload::Set(v) if weights.is_empty() => Ok(v.len() as u128),
+            Workload::Set(v) => v
+                .iter()
+                .try_fold(0, |acc, s| checked_add(acc, s.weight(weights)?)),
+            Workload::Stream(_) | Workload::Filter(..) | Workload::Dedup(..) => {
+                Err(CountError::Unknown)
+            }
+            Workload::Plug(wkld, hole, pegs, _, mode) => {
+                // a lexicographic plug also fills in the instances of its hole that the
+                // pegs bring along, over and over again
+                if self.as_lexicographic_plug().is_some() && pegs.may_contain_hole(hole) {
+                    return Err(CountError::Unknown);
+                }
+                let Some(weight) = pegs.peg_weight(weights)? else {
+                    return Ok(0);
+                };
+                // with uniform or commutative holes, how the holes of enclosing plugs in
+                // the pegs are filled in depends on which pegs are chosen together
+                if *mode != PlugMode::Independent && weight != pegs.count()? {
+                    return Err(CountError::Unknown);
+                }
+                let mut weights = weights.clone();
+                weights.push((hole, weight, mode));
+                wkld.weighted_count(&weights)
+            }
+            Workload::PlugMany(wkld, plugs) => {
+                let mut template_weights = weights.clone();
+                for (hole, pegs) in plugs {
+                    let Some(weight) = pegs.peg_weight(weights)? else {
+                        return Ok(0);
+                    };
+                    template_weights.push((hole, weight, &PlugMode::Independent));
+                }
+                wkld.weighted_count(&template_weights)
+            }
+            Workload::Append(wklds) | Workload::Interleave(wklds) => wklds
+                .iter()
+                .try_fold(0, |acc, w| checked_add(acc, w.weighted_count(weights)?)),
+        }
+    }
+
+    /// The weight of one instance of a hole plugged with these pegs, or `None` if there
+    /// are no pegs at all. In that case nothing is produced, not even the templates
+    /// without the hole.
+    pub(crate) fn peg_weight(&self, weights: &Weights<A>) -> Result<Option<u128>, CountError> {
+        match self.count()? {
+            0 => Ok(None),
+            n if weights.is_empty() => Ok(Some(n)),
+            n => {
+                let weight = self.weighted_count(weights)?;
+                // pegs that bring along instances of a uniform or commutative hole of an
+                // enclosing plug change how many ways there are to fill those in, in a
+                // way that a weight per instance can't express
+                let dependent = weights
+                    .iter()
+                    .any(|(.., mode)| **mode != PlugMode::Independent);
+                if dependent && weight != n {
+                    return Err(CountError::Unknown);
+                }
+                Ok(Some(weight))
+            }
+        }
+    }
+
+    /// Whether some term of this workload may contain an instance of `hole`, as far as
+    /// can be told without enumerating it. Streams can't be looked into, so they may.
+    pub(crate) fn may_contain_hole(&self, hole: &A) -> bool {
+        match self {
+            Workload::Set(v) => v.iter().any(|s| s.occurrences(hole) > 0),
+            Workload::Stream(_) => true,
+            Workload::Plug(wkld, h, pegs, ..) => {
+                (h != hole && wkld.may_contain_hole(hole)) || pegs.may_contain_hole(hole)
+            }
+            Workload::PlugMany(wkld, plugs) => {
+                (plugs.iter().all(|(h, _)| h != hole) && wkld.may_contain_hole(hole))
+                    || plugs.iter().any(|(_, pegs)| pegs.may_contain_hole(hole))
+            }
+            Workload::Filter(wkld, _) | Workload::Dedup(wkld, _) => wkld.may_contain_hole(hole),
+            Workload::Append(wklds) | Workload::Interleave(wklds) => {
+                wklds.iter().any(|w| w.may_contain_hole(hole))
+            }
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::{collections::HashSet, fmt::Debug, sync::Arc};
+
+use crate::{Atom, Sexp};
+
+/// Decides which atoms are variables, for [`Dedup::Renaming`].
+pub enum Vars<A = String> {
+    /// The atoms in the list.
+    Atoms(Vec<A>),
+    /// The atoms for which the function returns `true`.
+    Custom(Arc<dyn Fn(&A) -> bool + Send + Sync>),
+}
+
+impl<A: Atom> Vars<A> {
+    pub fn custom(f: impl Fn(&A) -> bool + Send + Sync + 'static) -> Self {
+        Vars::Custom(Arc::new(f))
+    }
+
+    pub fn contains(&self, atom: &A) -> bool {
+        match self {
+            Vars::Atoms(atoms) => atoms.iter().any(|a| a == atom),
+            Vars::Custom(f) => f(atom),
+        }
+    }
+}
+
+impl<A: Clone> Clone for Vars<A> {
+    fn clone(&self) -> Self {
+        match self {
+            Vars::Atoms(atoms) => Vars::Atoms(atoms.clone()),
+            Vars::Custom(f) => Vars::Custom(f.clone()),
+        }
+    }
+}
+
+impl<A: PartialEq> PartialEq for Vars<A> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Vars::Atoms(a), Vars::Atoms(b)) => a == b,
+            (Vars::Custom(f), Vars::Custom(g)) => Arc::ptr_eq(f, g),
+            _ => false,
+        }
+    }
+}
+
+impl<A: Eq> Eq for Vars<A> {}
+
+impl<A: Debug> Debug for Vars<A> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Vars::Atoms(atoms) => f.debug_tuple("Atoms").field(atoms).finish(),
+            Vars::Custom(g) => write!(f, "Custom({:p})", Arc::as_ptr(g)),
+        }
+    }
+}
+
+/// Decides which terms a [`Workload::Dedup`] considers the same.
+///
+/// [`Workload::Dedup`]: crate::Workload::Dedup
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Dedup<A = String> {
+    /// Terms that are equal.
+    Exact,
+    /// Terms that are equal up to a consistent renaming of their variables. `(+ a b)`
+    /// and `(+ b a)` are the same, but `(+ a a)` and `(+ a b)` are not.
+    Renaming(Vars<A>),
+}
+
+impl<A: Atom> Dedup<A> {
+    /// Drop the terms of `iter` that are the same as an earlier one. Every term that is
+    /// kept is remembered, so this takes memory proportional to the number of distinct
+    /// terms. This is what [`Workload::Dedup`] does, for terms that don't come out of a
+    /// workload.
+    ///
+    /// [`Workload::Dedup`]: crate::Workload::Dedup
+    pub fn apply(
+        self,
+        iter: impl Iterator<Item = Sexp<A>> + 'static,
+    ) -> Box<dyn Iterator<Item = Sexp<A>>> {
+        match self {
+            Dedup::Exact => {
+                let mut seen = HashSet::new();
+                Box::new(iter.filter(move |sexp| seen.insert(sexp.clone())))
+            }
+            Dedup::Renaming(vars) => {
+                // variables become their index, and the other atoms stay as they are
+                let mut seen = HashSet::new();
+                Box::new(iter.filter(move |sexp| {
+                    seen.insert(sexp.rename_vars(&|a| vars.contains(a), &Err, &|a| Ok(a.clone())))
+                }))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dedup(dedup: Dedup, terms: &[&str]) -> Vec<String> {
+        let terms: Vec<Sexp> = terms.iter().map(|s| s.parse().unwrap()).collect();
+        dedup
+            .apply(terms.into_iter())
+            .map(|sexp| sexp.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn renaming() {
+        let vars = || Dedup::Renaming(Vars::Atoms(vec!["a".to_string(), "b".to_string()]));
+        assert_eq!(dedup(vars(), &["(+ a b)", "(+ b a)"]), ["(+ a b)"]);
+        assert_eq!(
+            dedup(vars(), &["(+ a a)", "(+ a b)", "(+ b b)"]),
+            ["(+ a a)", "(+ a b)"]
+        );
+        // atoms that aren't variables have to be equal
+        assert_eq!(
+            dedup(vars(), &["(+ a c)", "(+ b c)", "(+ a d)"]),
+            ["(+ a c)", "(+ a d)"]
+        );
+        // holes are neither variables nor renamed variables
+        assert_eq!(
+            dedup(
+                vars(),
+                &["(f a ?0)", "(f b ?0)", "(f ?0 a)", "(f ?0 ?0)", "(f a a)"]
+            ),
+            ["(f a ?0)", "(f ?0 a)", "(f ?0 ?0)", "(f a a)"]
+        );
+        assert_eq!(
+            dedup(Dedup::Exact, &["(+ a b)", "(+ b a)", "(+ a b)"]),
+            ["(+ a b)", "(+ b a)"]
+        );
+    }
+
+    #[test]
+    fn canonicalize_vars() {
+        let is_var = |a: &str| ["a", "b", "x", "y"].contains(&a);
+        let canonical = |s: &str| {
+            s.parse::<Sexp>()
+                .unwrap()
+                .canonicalize_vars(is_var)
+                .to_string()
+        };
+        assert_eq!(canonical("(+ a b)"), canonical("(+ b a)"));
+        assert_eq!(canonical("(+ x (f y x))"), "(+ ?0 (f ?1 ?0))");
+        assert_ne!(canonical("(+ a a)"), canonical("(+ a b)"));
+        assert_eq!(canonical("(g ?A a 10)"), "(g ?A ?0 10)");
+    }
+}
@@ -0,0 +1,472 @@
+use std::{collections::HashMap, str::FromStr};
+
+use crate::{
+    parse::{ParseError, ParseErrorKind, Reader},
+    Dedup, Filter, PlugMode, Sexp, Traversal, Vars, Workload,
+};
+
+/// Punctuation of the workload language. These can't appear inside atoms of a
+/// workload file.
+const DELIMITERS: &[char] = &['{', '}', ',', '='];
+
+const KEYWORDS: &[&str] = &[
+    "let",
+    "plug",
+    "filter",
+    "dedup",
+    "iter_metric",
+    "append",
+    "interleave",
+];
+
+struct Parser<'a> {
+    reader: Reader<'a>,
+    env: HashMap<String, Workload>,
+}
+
+impl Parser<'_> {
+    fn message(&self, pos: (usize, usize), msg: String) -> ParseError {
+        self.reader.error_at(pos, ParseErrorKind::Message(msg))
+    }
+
+    fn program(&mut self) -> Result<Workload, ParseError> {
+        loop {
+            self.reader.skip_trivia();
+            if self.reader.eat_keyword("let") {
+                self.binding()?;
+            } else {
+                let wkld = self.expr()?;
+                self.reader.skip_trivia();
+                return if self.reader.at_eof() {
+                    Ok(wkld)
+                } else {
+                    Err(self.reader.error(ParseErrorKind::TrailingInput))
+                };
+            }
+        }
+    }
+
+    /// `let <name> = <expr>`, after the `let` has been consumed. Later bindings shadow
+    /// earlier ones.
+    fn binding(&mut self) -> Result<(), ParseError> {
+        self.reader.skip_trivia();
+        let pos = self.reader.position();
+        let name = self
+            .reader
+            .atom()
+            .ok_or_else(|| self.message(pos, "expected a name after `let`".to_string()))?;
+        if KEYWORDS.contains(&name.as_str()) {
+            return Err(self.message(pos, format!("`{name}` is a keyword")));
+        }
+        self.reader.expect('=')?;
+        let wkld = self.expr()?;
+        self.env.insert(name, wkld);
+        Ok(())
+    }
+
+    fn expr(&mut self) -> Result<Workload, ParseError> {
+        self.reader.skip_trivia();
+        let pos = self.reader.position();
+        match self.reader.peek() {
+            None => Err(self.reader.error(ParseErrorKind::UnexpectedEof)),
+            Some('{') => self.set(),
+            Some(_) if self.reader.eat_keyword("plug") => self.plug(),
+            Some(_) if self.reader.eat_keyword("filter") => self.filter(),
+            Some(_) if self.reader.eat_keyword("dedup") => self.dedup(),
+            Some(_) if self.reader.eat_keyword("iter_metric") => self.iter_metric(),
+            Some(_) if self.reader.eat_keyword("append") => Ok(Workload::Append(self.args()?)),
+            Some(_) if self.reader.eat_keyword("interleave") => {
+                Ok(Workload::Interleave(self.args()?))
+            }
+            Some(_) if self.reader.eat_keyword("let") => Err(self.message(
+                pos,
+                "`let` is only allowed before the final workload".to_string(),
+            )),
+            Some(c) => match self.reader.atom() {
+                Some(name) => self
+                    .env
+                    .get(&name)
+                    .cloned()
+                    .ok_or_else(|| self.message(pos, format!("unbound name `{name}`"))),
+                None => Err(self.message(pos, format!("unexpected `{c}`"))),
+            },
+        }
+    }
+
+    /// `{ <sexp>* }`
+    fn set(&mut self) -> Result<Workload, ParseError> {
+        let open = self.reader.position();
+        self.reader.expect('{')?;
+        let mut sexps = vec![];
+        loop {
+            self.reader.skip_trivia();
+            match self.reader.peek() {
+                None => return Err(self.message(open, "unclosed `{`".to_string())),
+                Some('}') => {
+                    self.reader.bump();
+                    return Ok(Workload::Set(sexps));
+                }
+                Some(_) => sexps.push(self.reader.sexp()?),
+            }
+        }
+    }
+
+    /// `plug(<expr>, <hole>, <expr> [, <traversal>] [, <mode>])`, or
+    /// `plug(<expr>, <hole>, <expr>, <hole>, <expr>, ...)` for several holes at once,
+    /// after the `plug` has been consumed.
+    fn plug(&mut self) -> Result<Workload, ParseError> {
+        self.reader.expect('(')?;
+        let template = self.expr()?;
+        self.reader.expect(',')?;
+        let (_, hole) = self.word("a hole name")?;
+        self.reader.expect(',')?;
+        let mut plugs = vec![(hole_name(hole), self.expr()?)];
+        let mut traversal = None;
+        let mut mode = None;
+        let mut options = None;
+        loop {
+            self.reader.skip_trivia();
+            if self.reader.peek() != Some(',') {
+                break;
+            }
+            self.reader.bump();
+            let (pos, name) = self.word("a hole name, a traversal or a plug mode")?;
+            if let Ok(t) = name.parse::<Traversal>() {
+                if traversal.replace((pos, t)).is_some() {
+                    return Err(self.message(pos, "more than one traversal".to_string()));
+                }
+                options.get_or_insert(pos);
+            } else if let Ok(m) = self.plug_mode(pos, &name)? {
+                if mode.replace(m).is_some() {
+                    return Err(self.message(pos, "more than one plug mode".to_string()));
+                }
+                options.get_or_insert(pos);
+            } else if options.is_some() {
+                return Err(self.message(
+                    pos,
+                    format!("expected a traversal or a plug mode, got `{name}`"),
+                ));
+            } else {
+                // another hole, with its pegs
+                self.reader.expect(',')?;
+                let name = hole_name(name);
+                if plugs.iter().any(|(hole, _)| *hole == name) {
+                    return Err(self.message(pos, format!("`{name}` is plugged twice")));
+                }
+                plugs.push((name, self.expr()?));
+            }
+        }
+        self.reader.expect(')')?;
+
+        if let (Some((pos, t)), Some(PlugMode::Commutative(_))) = (traversal, &mode) {
+            if t.is_fair() {
+                return Err(self.message(
+                    pos,
+                    format!("a commutative plug can't use the fair traversal `{t}`"),
+                ));
+            }
+        }
+        match (plugs.len(), options) {
+            (1, _) => {
+                let (hole, pegs) = plugs.pop().unwrap();
+                Ok(Workload::Plug(
+                    Box::new(template),
+                    hole,
+                    Box::new(pegs),
+                    traversal.map_or_else(Traversal::default, |(_, t)| t),
+                    mode.unwrap_or_default(),
+                ))
+            }
+            (_, Some(pos)) => Err(self.message(
+                pos,
+                "a traversal or a plug mode can only be given when plugging a single hole"
+                    .to_string(),
+            )),
+            (_, None) => {
+                let plugs: Vec<(&str, Workload)> = plugs
+                    .iter()
+                    .map(|(hole, pegs)| (hole.as_str(), pegs.clone()))
+                    .collect();
+                Ok(template.plug_many(&plugs))
+            }
+        }
+    }
+
+    /// A plug mode named `name`, which for `commutative` is followed by the list of
+    /// operators. Returns `Ok(Err(_))` if `name` isn't a plug mode at all.
+    fn plug_mode(
+        &mut self,
+        pos: (usize, usize),
+        name: &str,
+    ) -> Result<Result<PlugMode, String>, ParseError> {
+        if name != "commutative" {
+            return Ok(name.parse());
+        }
+        let ops = self.reader.sexp()?;
+        format!("{name} {ops}")
+            .parse()
+            .map(Ok)
+            .map_err(|msg| self.message(pos, msg))
+    }
+
+    /// `(<expr>, ...)`, the arguments of `append` and `interleave`.
+    fn args(&mut self) -> Result<Vec<Workload>, ParseError> {
+        self.reader.expect('(')?;
+        let mut wklds = vec![self.expr()?];
+        loop {
+            self.reader.skip_trivia();
+            if self.reader.peek() == Some(',') {
+                self.reader.bump();
+                wklds.push(self.expr()?);
+            } else {
+                self.reader.expect(')')?;
+                return Ok(wklds);
+            }
+        }
+    }
+
+    /// `iter_metric(<expr>, <nonterminal>, <metric>, <n>)`, after the `iter_metric` has
+    /// been consumed.
+    fn iter_metric(&mut self) -> Result<Workload, ParseError> {
+        self.reader.expect('(')?;
+        let grammar = self.expr()?;
+        self.reader.expect(',')?;
+        let (_, start) = self.word("a nonterminal")?;
+        let start = hole_name(start);
+        self.reader.expect(',')?;
+        let (pos, metric) = self.word("a metric")?;
+        let metric = metric.parse().map_err(|e| self.message(pos, e))?;
+        self.reader.expect(',')?;
+        let n = self.number()?;
+        self.reader.expect(')')?;
+        Ok(grammar.iter_metric(&start, metric, n))
+    }
+
+    fn number(&mut self) -> Result<usize, ParseError> {
+        let (pos, n) = self.word("a number")?;
+        n.parse()
+            .map_err(|_| self.message(pos, format!("expected a number, got `{n}`")))
+    }
+
+    /// An atom along with where it starts, or an error saying that `what` was expected.
+    fn word(&mut self, what: &str) -> Result<((usize, usize), String), ParseError> {
+        self.reader.skip_trivia();
+        let pos = self.reader.position();
+        self.reader
+            .atom()
+            .map(|word| (pos, word))
+            .ok_or_else(|| self.message(pos, format!("expected {what}")))
+    }
+
+    /// `filter(<expr>, <predicate>)`, after the `filter` has been consumed.
+    fn filter(&mut self) -> Result<Workload, ParseError> {
+        self.reader.expect('(')?;
+        let wkld = self.expr()?;
+        self.reader.expect(',')?;
+        let filter = self.predicate()?;
+        self.reader.expect(')')?;
+        Ok(wkld.filter(filter))
+    }
+
+    /// `dedup(<expr> [, vars (<atom>*)])`, after the `dedup` has been consumed.
+    fn dedup(&mut self) -> Result<Workload, ParseError> {
+        self.reader.expect('(')?;
+        let wkld = self.expr()?;
+        self.reader.skip_trivia();
+        if self.reader.peek() != Some(',') {
+            self.reader.expect(')')?;
+            return Ok(wkld.dedup(Dedup::Exact));
+        }
+        self.reader.bump();
+        let (pos, word) = self.word("`vars`")?;
+        if word != "vars" {
+            return Err(self.message(pos, format!("expected `vars`, got `{word}`")));
+        }
+        self.reader.skip_trivia();
+        let pos = self.reader.position();
+        let vars = match self.reader.sexp()? {
+            Sexp::List(list) => list
+                .into_iter()
+                .map(|var| match var {
+                    Sexp::Atom(var) => Ok(var),
+                    Sexp::Hole(_) | Sexp::List(_) => {
+                        Err(self.message(pos, format!("variable `{var}` is not an atom")))
+                    }
+                })
+                .collect::<Result<_, _>>()?,
+            Sexp::Atom(_) | Sexp::Hole(_) => {
+                return Err(self.message(pos, "expected a list of variables".to_string()))
+            }
+        };
+        self.reader.expect(')')?;
+        Ok(wkld.dedup(Dedup::Renaming(Vars::Atoms(vars))))
+    }
+
+    /// One of `contains <atom>`, `excludes <atom>`, `matches <sexp>`, or
+    /// `<metric> < <n>` / `<metric> > <n>`.
+    fn predicate(&mut self) -> Result<Filter, ParseError> {
+        let (pos, head) = self.word("a predicate")?;
+        match head.as_str() {
+            "contains" => Ok(Filter::Contains(self.word("an atom")?.1)),
+            "excludes" => Ok(Filter::Excludes(self.word("an atom")?.1)),
+            "matches" => Ok(Filter::Matches(self.reader.sexp()?)),
+            _ => {
+                let metric = head.parse().map_err(|e| self.message(pos, e))?;
+                let (pos, op) = self.word("`<` or `>`")?;
+                let compare = match op.as_str() {
+                    "<" => Filter::LessThan,
+                    ">" => Filter::GreaterThan,
+                    _ => return Err(self.message(pos, format!("expected `<` or `>`, got `{op}`"))),
+                };
+                Ok(compare(metric, self.number()?))
+            }
+        }
+    }
+}
+
+/// The name of a hole given to a plug, which may be written with its `?`.
+fn hole_name(word: String) -> String {
+    match word.strip_prefix('?') {
+        Some(name) if !name.is_empty() => name.to_string(),
+        _ => word,
+    }
+}
+
+impl Workload {
+    /// Parse a workload written in the workload language:
+    ///
+    /// ```text
+    /// program   := ("let" NAME "=" expr)* expr
+    /// expr      := "{" sexp* "}"
+    ///            | "plug" "(" expr "," HOLE "," expr ("," TRAVERSAL)? ("," mode)? ")"
+    ///            | "plug" "(" expr ("," HOLE "," expr)+ ")"
+    ///            | "filter" "(" expr "," predicate ")"
+    ///            | "dedup" "(" expr ("," "vars" "(" ATOM* ")")? ")"
+    ///            | "iter_metric" "(" expr "," NONTERMINAL "," METRIC "," NUMBER ")"
+    ///            | ("append" | "interleave") "(" expr ("," expr)* ")"
+    ///            | NAME
+    /// mode      := "independent" | "uniform" | "commutative" "(" ATOM* ")"
+    /// predicate := "contains" ATOM | "excludes" ATOM | "matches" sexp
+    ///            | METRIC "<" NUMBER | METRIC ">" NUMBER
+    /// ```
+    ///
+    /// A `{ ... }` set lists s-expressions in the syntax accepted by [`Sexp::parse`],
+    /// where holes are written `?A`, `plug(w, A, pegs)` (or `plug(w, ?A, pegs)`) is
+    /// [`Workload::plug`], `plug(w, A, pegs, diagonal)` is [`Workload::plug_with`] using
+    /// the [`Traversal`] with that name (`depth-first`, `reverse-lex`, `breadth-first`
+    /// or `diagonal`), `plug(w, A, pegs, uniform)` fills in every instance of `?A` with
+    /// the same peg (see [`PlugMode`]; the default is `independent`),
+    /// `plug(w, A, pegs, commutative (+ *))` only produces one ordering of the pegs
+    /// among the arguments of `+` and `*`, `plug(w, A, pegs_a, B, pegs_b)` is
+    /// [`Workload::plug_many`], `filter(w, pred)` is [`Workload::filter`] with the
+    /// corresponding [`Filter`] (e.g. `size < 5`, where the `<` has to be surrounded by
+    /// whitespace), `dedup(w)` and `dedup(w, vars (a b))` are [`Workload::dedup`] with
+    /// [`Dedup::Exact`] and with [`Dedup::Renaming`] of the listed atoms,
+    /// `iter_metric(grammar, EXPR, size, 5)` is [`Workload::iter_metric`],
+    /// `append(w1, w2, ...)` and `interleave(w1, w2, ...)` are [`Workload::Append`] and
+    /// [`Workload::Interleave`], and a name refers to the closest preceding `let` with
+    /// that name. Comments start with `;` and run to the end of the line. For example:
+    ///
+    /// ```text
+    /// let consts = {0 1 2}
+    /// let vars = {a b}
+    /// plug(plug({(+ ?A ?B)}, A, consts), B, vars)
+    /// ```
+    ///
+    /// Because `{`, `}`, `,` and `=` are punctuation here, atoms in a workload file can't
+    /// contain them.
+    ///
+    /// [`Sexp::parse`]: crate::Sexp::parse
+    pub fn parse(src: &str) -> Result<Self, ParseError> {
+        Parser {
+            reader: Reader::with_delimiters(src, DELIMITERS),
+            env: HashMap::new(),
+        }
+        .program()
+    }
+}
+
+impl FromStr for Workload {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Workload::parse(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The message of the error for `src`, and where it is.
+    fn error(src: &str) -> (String, usize, usize) {
+        let e = Workload::parse(src).unwrap_err();
+        let ParseErrorKind::Message(msg) = e.kind else {
+            panic!("{src}: {e}");
+        };
+        (msg, e.line, e.column)
+    }
+
+    #[test]
+    fn commutative_plugs_are_not_fair() {
+        for t in [Traversal::BreadthFirst, Traversal::Diagonal] {
+            let src = format!("plug({{(+ ?A ?A)}}, A, {{0 1}}, commutative (+),\n  {t})");
+            let msg = format!("a commutative plug can't use the fair traversal `{t}`");
+            assert_eq!(error(&src), (msg, 2, 3), "{src}");
+        }
+        let src = "plug({(+ ?A ?A)}, A, {0 1}, reverse-lex, commutative (+))";
+        assert_eq!(Workload::parse(src).unwrap().count(), Ok(3));
+    }
+
+    fn terms(src: &str) -> Vec<String> {
+        let wkld = Workload::parse(src).unwrap();
+        wkld.into_iter().map(|sexp| sexp.to_string()).collect()
+    }
+
+    #[test]
+    fn let_shadows() {
+        let src = "let x = {a}\nlet x = append(x, {b})\nlet y = x\nlet x = {c}\nappend(y, x)";
+        assert_eq!(terms(src), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn errors() {
+        let cases = [
+            ("let plug = {a}\nplug", "`plug` is a keyword", 1, 5),
+            ("let x = {a}\nplug(y, A, x)", "unbound name `y`", 2, 6),
+            ("append({a},\n  {b (c)", "unclosed `{`", 2, 3),
+            (
+                "plug({?A}, A, {0}, diagonal, depth-first)",
+                "more than one traversal",
+                1,
+                30,
+            ),
+            (
+                "plug({?A}, A, {0}, uniform, B, {1})",
+                "expected a traversal or a plug mode, got `B`",
+                1,
+                29,
+            ),
+            (
+                "filter({a}, size <5)",
+                "expected `<` or `>`, got `<5`",
+                1,
+                18,
+            ),
+        ];
+        for (src, msg, line, column) in cases {
+            assert_eq!(error(src), (msg.to_string(), line, column), "{src}");
+        }
+    }
+
+    #[test]
+    fn options_and_holes() {
+        // a hole that is named like a traversal is written with its `?`
+        let src = "plug({(f ?A ?diagonal)}, A, {0}, ?diagonal, {1})";
+        assert_eq!(terms(src), ["(f 0 1)"]);
+        let src = "plug({(f ?A ?B)}, A, {0 1}, B, {2})";
+        assert_eq!(terms(src), ["(f 0 2)", "(f 1 2)"]);
+        let src = "plug({(f ?A ?diagonal)}, A, {0}, diagonal)";
+        assert_eq!(terms(src), ["(f 0 ?diagonal)"]);
+    }
+}
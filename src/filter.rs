@@ -0,0 +1,90 @@
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
+
+use crate::{Atom, Metric, Sexp};
+
+type Test<A> = dyn Fn(&Sexp<A>) -> bool + Send + Sync;
+
+/// A user-supplied test for [`Filter::Custom`].
+pub struct Predicate<A = String>(Arc<Test<A>>);
+
+impl<A> Predicate<A> {
+    pub fn new(f: impl Fn(&Sexp<A>) -> bool + Send + Sync + 'static) -> Self {
+        Predicate(Arc::new(f))
+    }
+}
+
+impl<A> Clone for Predicate<A> {
+    fn clone(&self) -> Self {
+        Predicate(self.0.clone())
+    }
+}
+
+impl<A> PartialEq for Predicate<A> {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl<A> Eq for Predicate<A> {}
+
+impl<A> Debug for Predicate<A> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Predicate({:p})", Arc::as_ptr(&self.0))
+    }
+}
+
+/// Decides which terms a [`Workload::Filter`] keeps.
+///
+/// [`Workload::Filter`]: crate::Workload::Filter
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Filter<A = String> {
+    /// Keep terms with at least one atom equal to this one.
+    Contains(A),
+    /// Keep terms with no atom equal to this one.
+    Excludes(A),
+    /// Keep terms whose metric is strictly less than the bound.
+    LessThan(Metric, usize),
+    /// Keep terms whose metric is strictly greater than the bound.
+    GreaterThan(Metric, usize),
+    /// Keep terms that match a pattern. The holes of the pattern, like `?x`, match any
+    /// subterm; every occurrence of the same hole has to match the same subterm. Atoms
+    /// only match themselves.
+    Matches(Sexp<A>),
+    /// Keep terms for which the predicate returns `true`.
+    Custom(Predicate<A>),
+}
+
+impl<A: Atom> Filter<A> {
+    pub fn custom(f: impl Fn(&Sexp<A>) -> bool + Send + Sync + 'static) -> Self {
+        Filter::Custom(Predicate::new(f))
+    }
+
+    pub fn test(&self, sexp: &Sexp<A>) -> bool {
+        match self {
+            Filter::Contains(atom) => sexp.contains_atom(atom),
+            Filter::Excludes(atom) => !sexp.contains_atom(atom),
+            Filter::LessThan(metric, bound) => sexp.measure(*metric) < *bound,
+            Filter::GreaterThan(metric, bound) => sexp.measure(*metric) > *bound,
+            Filter::Matches(pattern) => matches(pattern, sexp, &mut HashMap::new()),
+            Filter::Custom(Predicate(f)) => f(sexp),
+        }
+    }
+}
+
+fn matches<'a, A: Atom>(
+    pattern: &'a Sexp<A>,
+    sexp: &'a Sexp<A>,
+    bindings: &mut HashMap<&'a A, &'a Sexp<A>>,
+) -> bool {
+    match (pattern, sexp) {
+        (Sexp::Hole(var), _) => *bindings.entry(var).or_insert(sexp) == sexp,
+        (Sexp::List(pats), Sexp::List(list)) => {
+            pats.len() == list.len()
+                && pats
+                    .iter()
+                    .zip(list)
+                    .all(|(pat, sexp)| matches(pat, sexp, bindings))
+        }
+        _ => pattern == sexp,
+    }
+}
@@ -0,0 +1,425 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt::{Debug, Display},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex, OnceLock, Weak},
+};
+
+use crate::{Sexp, Workload};
+
+/// An interned atom. Every distinct name is stored once, for the lifetime of the
+/// program, so symbols are copied, compared and hashed as integers.
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy)]
+pub struct Symbol(u32);
+
+#[derive(Default)]
+struct Symbols {
+    ids: HashMap<&'static str, u32>,
+    names: Vec<&'static str>,
+}
+
+fn symbols() -> &'static Mutex<Symbols> {
+    static SYMBOLS: OnceLock<Mutex<Symbols>> = OnceLock::new();
+    SYMBOLS.get_or_init(Default::default)
+}
+
+impl Symbol {
+    pub fn new(name: &str) -> Self {
+        let mut symbols = symbols().lock().unwrap();
+        if let Some(&id) = symbols.ids.get(name) {
+            return Symbol(id);
+        }
+        let name: &'static str = Box::leak(name.into());
+        let id = symbols.names.len() as u32;
+        symbols.names.push(name);
+        symbols.ids.insert(name, id);
+        Symbol(id)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        symbols().lock().unwrap().names[self.0 as usize]
+    }
+}
+
+impl Display for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl Debug for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Symbol({:?})", self.as_str())
+    }
+}
+
+/// A hash-consed s-expression: there is only ever one live copy of every term, which
+/// all equal terms point to. Equality and hashing look at that pointer and a cached
+/// hash, so they take constant time whatever the size of the term, and cloning a term
+/// or sharing it as a subterm of other terms doesn't copy anything.
+///
+/// Terms are built through a global table, which is locked for every new list or
+/// atom, and which only holds weak references, so terms are freed as usual once they
+/// are dropped.
+#[derive(Clone)]
+pub struct Term(Arc<Node>);
+
+struct Node {
+    hash: u64,
+    kind: Kind,
+}
+
+/// The children of a list are hash-consed already, so comparing them by pointer is
+/// enough.
+#[derive(PartialEq)]
+enum Kind {
+    Atom(Symbol),
+    Hole(Symbol),
+    List(Box<[Term]>),
+}
+
+#[derive(Default)]
+struct Terms {
+    nodes: HashMap<u64, Vec<Weak<Node>>>,
+    /// The number of hashes after the last time the table was swept, to sweep it
+    /// again once it has doubled.
+    swept: usize,
+}
+
+fn terms() -> &'static Mutex<Terms> {
+    static TERMS: OnceLock<Mutex<Terms>> = OnceLock::new();
+    TERMS.get_or_init(Default::default)
+}
+
+impl Term {
+    pub fn atom(name: &str) -> Self {
+        Term::symbol(Symbol::new(name))
+    }
+
+    pub fn symbol(symbol: Symbol) -> Self {
+        Term::cons(Kind::Atom(symbol))
+    }
+
+    pub fn hole(name: &str) -> Self {
+        Term::cons(Kind::Hole(Symbol::new(name)))
+    }
+
+    pub fn list(items: impl IntoIterator<Item = Term>) -> Self {
+        Term::cons(Kind::List(items.into_iter().collect()))
+    }
+
+    fn cons(kind: Kind) -> Self {
+        let mut hasher = DefaultHasher::new();
+        match &kind {
+            Kind::Atom(symbol) => (0u8, symbol).hash(&mut hasher),
+            Kind::Hole(symbol) => (2u8, symbol).hash(&mut hasher),
+            Kind::List(items) => {
+                1u8.hash(&mut hasher);
+                items.iter().for_each(|item| item.0.hash.hash(&mut hasher));
+            }
+        }
+        let hash = hasher.finish();
+
+        let mut terms = terms().lock().unwrap();
+        let bucket = terms.nodes.entry(hash).or_default();
+        bucket.retain(|node| node.strong_count() > 0);
+        if let Some(node) = bucket
+            .iter()
+            .filter_map(Weak::upgrade)
+            .find(|node| node.kind == kind)
+        {
+            return Term(node);
+        }
+        let node = Arc::new(Node { hash, kind });
+        bucket.push(Arc::downgrade(&node));
+        if terms.nodes.len() > 2 * terms.swept.max(1024) {
+            terms.nodes.retain(|_, bucket| {
+                bucket.retain(|node| node.strong_count() > 0);
+                !bucket.is_empty()
+            });
+            terms.swept = terms.nodes.len();
+        }
+        Term(node)
+    }
+
+    pub fn as_atom(&self) -> Option<Symbol> {
+        match self.0.kind {
+            Kind::Atom(symbol) => Some(symbol),
+            Kind::Hole(_) | Kind::List(_) => None,
+        }
+    }
+
+    pub fn as_hole(&self) -> Option<Symbol> {
+        match self.0.kind {
+            Kind::Hole(symbol) => Some(symbol),
+            Kind::Atom(_) | Kind::List(_) => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Term]> {
+        match &self.0.kind {
+            Kind::Atom(_) | Kind::Hole(_) => None,
+            Kind::List(items) => Some(items),
+        }
+    }
+
+    pub fn to_sexp(&self) -> Sexp {
+        match &self.0.kind {
+            Kind::Atom(symbol) => Sexp::Atom(symbol.as_str().to_string()),
+            Kind::Hole(symbol) => Sexp::Hole(symbol.as_str().to_string()),
+            Kind::List(items) => Sexp::List(items.iter().map(Term::to_sexp).collect()),
+        }
+    }
+
+    /// The term with its first instance of the hole `needle` replaced by `new`, or
+    /// `None` if it doesn't contain `needle`. Only the lists on the path to that
+    /// instance are rebuilt; everything else is shared with `self`.
+    pub fn replace_first(&self, needle: Symbol, new: &Term) -> Option<Term> {
+        self.replace(needle, new, false)
+    }
+
+    /// Like [`Term::replace_first`], for the last instance of `needle`.
+    pub fn replace_last(&self, needle: Symbol, new: &Term) -> Option<Term> {
+        self.replace(needle, new, true)
+    }
+
+    fn replace(&self, needle: Symbol, new: &Term, from_right: bool) -> Option<Term> {
+        match &self.0.kind {
+            Kind::Hole(symbol) if *symbol == needle => Some(new.clone()),
+            Kind::Atom(_) | Kind::Hole(_) => None,
+            Kind::List(items) => {
+                let replaced = |(i, item): (usize, &Term)| {
+                    item.replace(needle, new, from_right).map(|item| (i, item))
+                };
+                let (i, item) = if from_right {
+                    items.iter().enumerate().rev().find_map(replaced)?
+                } else {
+                    items.iter().enumerate().find_map(replaced)?
+                };
+                let mut items = items.to_vec();
+                items[i] = item;
+                Some(Term::list(items))
+            }
+        }
+    }
+}
+
+impl PartialEq for Term {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Term {}
+
+impl Hash for Term {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.0.hash)
+    }
+}
+
+impl From<&Sexp> for Term {
+    fn from(sexp: &Sexp) -> Self {
+        match sexp {
+            Sexp::Atom(a) => Term::atom(a),
+            Sexp::Hole(h) => Term::hole(h),
+            Sexp::List(list) => Term::list(list.iter().map(Term::from)),
+        }
+    }
+}
+
+impl From<&Term> for Sexp {
+    fn from(term: &Term) -> Self {
+        term.to_sexp()
+    }
+}
+
+impl Display for Term {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.0.kind {
+            Kind::Atom(symbol) => write!(f, "{symbol}"),
+            Kind::Hole(symbol) => write!(f, "?{symbol}"),
+            Kind::List(items) => {
+                write!(f, "(")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl Debug for Term {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Term({self})")
+    }
+}
+
+/// The depth-first substitution of [`SexpSubstIter`], over [`Term`]s: every step
+/// shares the template it fills in with its parent, instead of copying it.
+///
+/// [`SexpSubstIter`]: crate::SexpSubstIter
+pub struct TermSubstIter<I, F>
+where
+    I: Iterator<Item = Term>,
+    F: Fn() -> I,
+{
+    needle: Symbol,
+    from_right: bool,
+    spawn_iterator: F,
+    stack: Vec<(Term, I)>,
+}
+
+impl<I, F> TermSubstIter<I, F>
+where
+    I: Iterator<Item = Term>,
+    F: Fn() -> I,
+{
+    pub(crate) fn new(template: Term, needle: Symbol, from_right: bool, spawn_iterator: F) -> Self {
+        let pegs = spawn_iterator();
+        TermSubstIter {
+            needle,
+            from_right,
+            spawn_iterator,
+            stack: vec![(template, pegs)],
+        }
+    }
+}
+
+impl<I, F> Iterator for TermSubstIter<I, F>
+where
+    I: Iterator<Item = Term>,
+    F: Fn() -> I,
+{
+    type Item = Term;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (template, pegs) = self.stack.last_mut()?;
+            let Some(peg) = pegs.next() else {
+                self.stack.pop();
+                continue;
+            };
+            match template.replace(self.needle, &peg, self.from_right) {
+                Some(child) => {
+                    let pegs = (self.spawn_iterator)();
+                    self.stack.push((child, pegs));
+                }
+                // no instances left: every peg would produce the template itself, but
+                // it only counts once
+                None => return self.stack.pop().map(|(template, _)| template),
+            }
+        }
+    }
+}
+
+/// The pegs of a plug, as terms. Like [`SharedPegs`], sets are converted once and
+/// shared.
+///
+/// [`SharedPegs`]: crate::workload::SharedPegs
+#[derive(Clone)]
+enum TermPegs {
+    Set(Arc<[Term]>),
+    Workload(Arc<Workload>),
+}
+
+impl TermPegs {
+    fn iter(&self) -> Box<dyn Iterator<Item = Term>> {
+        match self {
+            TermPegs::Set(v) => {
+                let v = v.clone();
+                Box::new((0..v.len()).map(move |i| v[i].clone()))
+            }
+            TermPegs::Workload(wkld) => (**wkld).clone().into_terms(),
+        }
+    }
+}
+
+impl Workload {
+    /// Enumerate the workload like `into_iter`, but as hash-consed [`Term`]s, in the
+    /// same order. Sets, appends and depth-first plugs of a single independent hole
+    /// are enumerated as terms throughout, so that the terms share their subterms with
+    /// the templates and the pegs they were built from. Anything else is enumerated as
+    /// [`Sexp`]s and converted.
+    pub fn into_terms(self) -> Box<dyn Iterator<Item = Term>> {
+        let wkld = match self.into_lexicographic_plug() {
+            Ok((wkld, hole, pegs, from_right)) => {
+                let needle = Symbol::new(&hole);
+                let pegs = match pegs {
+                    Workload::Set(v) => TermPegs::Set(v.iter().map(Term::from).collect()),
+                    wkld => TermPegs::Workload(Arc::new(wkld)),
+                };
+                return Box::new(wkld.into_terms().flat_map(move |template| {
+                    let pegs = pegs.clone();
+                    TermSubstIter::new(template, needle, from_right, move || pegs.iter())
+                }));
+            }
+            Err(wkld) => wkld,
+        };
+        match wkld {
+            Workload::Set(v) => Box::new(v.into_iter().map(|sexp| Term::from(&sexp))),
+            Workload::Append(wklds) => Box::new(wklds.into_iter().flat_map(Workload::into_terms)),
+            wkld => Box::new(wkld.into_iter().map(|sexp| Term::from(&sexp))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn term(s: &str) -> Term {
+        Term::from(&s.parse::<Sexp>().unwrap())
+    }
+
+    #[test]
+    fn hash_consing() {
+        let built = Term::list([
+            Term::atom("f"),
+            Term::hole("A"),
+            Term::list([Term::atom("g")]),
+        ]);
+        assert!(Arc::ptr_eq(&built.0, &term("(f ?A (g))").0));
+        assert_eq!(term("(f ?A (g))").to_string(), "(f ?A (g))");
+        assert_ne!(term("(f A (g))"), built);
+        assert_ne!(term("(f ?A g)"), built);
+    }
+
+    #[test]
+    fn into_terms_is_into_iter() {
+        for src in [
+            "plug({(f ?A ?A) x (g ?A)}, A, {0 1 2})",
+            "plug({(f ?A (g ?A)) x}, A, {0 (s 1)}, reverse-lex)",
+            // the pegs contain the hole of the outer plug
+            "plug(plug({(f ?A ?B) (g ?B)}, A, {?B 0 (h ?B)}), B, {0 1})",
+            "plug(plug({(f ?A ?B ?A)}, A, {?B (h ?B)}, reverse-lex), B, {0 1 2})",
+            "append({a}, plug({(f ?A ?A)}, A, filter({0 1 (s 2)}, size < 2)))",
+            "plug({(f ?A ?A)}, A, {0 1 2}, diagonal)",
+        ] {
+            let wkld = Workload::parse(src).unwrap();
+            let expected: Vec<Sexp> = wkld.clone().into_iter().collect();
+            let found: Vec<Sexp> = wkld.into_terms().map(|t| t.to_sexp()).collect();
+            assert_eq!(found, expected, "{src}");
+        }
+    }
+
+    #[test]
+    fn replace() {
+        let a = Symbol::new("A");
+        let template = term("(f ?A (g ?A) (h ?B))");
+        let new = term("(s 0)");
+        let first = template.replace_first(a, &new).unwrap();
+        let last = template.replace_last(a, &new).unwrap();
+        assert_eq!(first, term("(f (s 0) (g ?A) (h ?B))"));
+        assert_eq!(last, term("(f ?A (g (s 0)) (h ?B))"));
+        // the subterms off the path are shared
+        assert_eq!(first.as_list().unwrap()[2], template.as_list().unwrap()[2]);
+        assert_eq!(last.as_list().unwrap()[3], template.as_list().unwrap()[3]);
+        assert_eq!(template.replace_first(Symbol::new("C"), &new), None);
+        assert_eq!(term("A").replace_last(a, &new), None);
+    }
+}
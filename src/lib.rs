@@ -0,0 +1,29 @@
+mod checkpoint;
+mod count;
+mod dedup;
+mod dsl;
+mod filter;
+mod intern;
+mod metric;
+mod par;
+mod parse;
+mod rank;
+mod sample;
+mod sexp;
+mod shard;
+mod subst;
+mod traversal;
+mod visit;
+mod workload;
+
+pub use checkpoint::{Checkpoint, Cursor};
+pub use count::CountError;
+pub use dedup::{Dedup, Vars};
+pub use filter::{Filter, Predicate};
+pub use intern::{Symbol, Term, TermSubstIter};
+pub use metric::Metric;
+pub use parse::{ParseError, ParseErrorKind};
+pub use sexp::{Atom, Sexp};
+pub use subst::{SexpPlugManyIter, SexpSubstIter};
+pub use traversal::{PlugMode, SexpLevelIter, Traversal};
+pub use workload::{Stream, Workload};
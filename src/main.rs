@@ -1,282 +1,293 @@
-use std::{collections::VecDeque, fmt::Display};
+use std::{
+    io::{self, BufWriter, Read, StdoutLock, Write},
+    process::ExitCode,
+};
 
-#[derive(PartialEq, Eq, Clone, Debug)]
-pub enum Sexp {
-    Atom(String),
-    List(Vec<Self>),
+use rand::{rngs::StdRng, SeedableRng};
+use workload_iter::{CountError, Dedup, Metric, Sexp, Vars, Workload};
+
+const USAGE: &str = "\
+usage: workload_iter [OPTIONS] [FILE]
+
+Enumerate the terms of the workload in FILE (or stdin when FILE is missing or `-`).
+
+options:
+  -n, --limit N       stop after N terms
+  -s, --offset N      skip the first N terms
+  -f, --format FMT    print terms as `sexp` (default) or `json`, one per line
+  -c, --count         print the number of terms instead of the terms
+      --stats         print the range and mean of every metric instead of the terms
+  -d, --dedup         drop terms that were already produced
+      --vars V,...    with `--dedup`, also drop terms that were already produced up to
+                      a renaming of these atoms
+      --sample N      draw N terms uniformly at random (without replacement)
+      --seed S        seed for `--sample` (default 0)
+      --shard I/N     only enumerate the I-th of N disjoint slices of the terms
+  -h, --help          print this message";
+
+#[derive(Clone, Copy)]
+enum Format {
+    Sexp,
+    Json,
+}
+
+struct Options {
+    file: Option<String>,
+    limit: Option<usize>,
+    offset: usize,
+    format: Format,
+    count: bool,
+    stats: bool,
+    dedup: bool,
+    vars: Vec<String>,
+    sample: Option<usize>,
+    seed: u64,
+    shard: Option<(usize, usize)>,
 }
 
-impl Display for Sexp {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Sexp::Atom(s) => write!(f, "{s}"),
-            Sexp::List(list) => {
-                write!(f, "(")?;
-                for (i, el) in list.iter().enumerate() {
-                    write!(f, "{el}")?;
-                    if i < list.len() - 1 {
-                        write!(f, " ")?;
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Option<Self>, String> {
+        let mut opts = Options {
+            file: None,
+            limit: None,
+            offset: 0,
+            format: Format::Sexp,
+            count: false,
+            stats: false,
+            dedup: false,
+            vars: vec![],
+            sample: None,
+            seed: 0,
+            shard: None,
+        };
+
+        fn number(flag: &str, value: Option<String>) -> Result<usize, String> {
+            let value = value.ok_or_else(|| format!("`{flag}` needs a value"))?;
+            value
+                .parse()
+                .map_err(|_| format!("`{flag}` expects a number, got `{value}`"))
+        }
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "-n" | "--limit" => opts.limit = Some(number(&arg, args.next())?),
+                "-s" | "--offset" => opts.offset = number(&arg, args.next())?,
+                "-f" | "--format" => {
+                    opts.format = match args.next().as_deref() {
+                        Some("sexp") => Format::Sexp,
+                        Some("json") => Format::Json,
+                        Some(other) => return Err(format!("unknown format `{other}`")),
+                        None => return Err(format!("`{arg}` needs a value")),
                     }
                 }
-                write!(f, ")")
+                "-c" | "--count" => opts.count = true,
+                "--stats" => opts.stats = true,
+                "-d" | "--dedup" => opts.dedup = true,
+                "--vars" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| format!("`{arg}` needs a value"))?;
+                    opts.vars = value.split(',').map(str::to_string).collect();
+                }
+                "--sample" => opts.sample = Some(number(&arg, args.next())?),
+                "--seed" => opts.seed = number(&arg, args.next())? as u64,
+                "--shard" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| format!("`{arg}` needs a value"))?;
+                    let shard = value
+                        .split_once('/')
+                        .and_then(|(i, n)| Some((i.parse().ok()?, n.parse().ok()?)))
+                        .filter(|(i, n)| i < n);
+                    match shard {
+                        Some(shard) => opts.shard = Some(shard),
+                        None => {
+                            return Err(format!("`{arg}` expects I/N with I < N, got `{value}`"))
+                        }
+                    }
+                }
+                flag if flag.starts_with('-') && flag != "-" => {
+                    return Err(format!("unknown option `{flag}`"))
+                }
+                file if opts.file.is_none() => opts.file = Some(file.to_string()),
+                extra => return Err(format!("unexpected argument `{extra}`")),
             }
         }
-    }
-}
-
-#[derive(Debug, Clone)]
-pub struct SexpSubstIter<I, F>
-where
-    I: Iterator<Item = Sexp>,
-    F: Fn() -> I,
-{
-    needle: String,
-    spawn_iterator: F,
-    stack: VecDeque<(Sexp, I)>,
-}
 
-impl<I, F> SexpSubstIter<I, F>
-where
-    I: Iterator<Item = Sexp>,
-    F: Fn() -> I,
-{
-    fn new<S: ToString>(inital_sexp: Sexp, needle: S, spawn_iterator: F) -> Self {
-        let initial_iter = spawn_iterator();
-        SexpSubstIter {
-            needle: needle.to_string(),
-            spawn_iterator,
-            stack: VecDeque::from([(inital_sexp, initial_iter)]),
+        if !opts.vars.is_empty() && !opts.dedup {
+            return Err("`--vars` only applies to `--dedup`".to_string());
+        }
+        if opts.count && opts.stats {
+            return Err("`--count` and `--stats` can't be combined".to_string());
+        }
+        if opts.sample.is_some() && opts.shard.is_some() {
+            return Err("`--sample` and `--shard` can't be combined".to_string());
         }
+        Ok(Some(opts))
     }
 }
 
-impl<I, F> Iterator for SexpSubstIter<I, F>
-where
-    I: Iterator<Item = Sexp>,
-    F: Fn() -> I,
-{
-    type Item = Sexp;
-
-    /// Intuitively, the thing that we want to do is perform a traversal of the leaves
-    /// of the following tree. Each level of the tree represents substituting the hole
-    /// variable (`A` in this case), with every instance of some other iterator.
-    ///
-    /// ```
-    ///             (+ A A)
-    ///            /       \
-    ///     (+ 0 A)         (+ 1 A)
-    ///       / \             / \
-    /// (+ 0 0) (+ 0 1) (+ 1 0) (+ 1 1)
-    /// ```
-    ///
-    /// The thing that makes it tricky to write this traversal in a lazy way is that we
-    /// don't know what this tree will look like up-front; it's lazily produced by
-    /// another iterator filling in the values of `A`.
-    ///
-    /// The trick is that we can use a stack to represent where we are in this tree
-    /// traversal, making sure that we have enough information to unfold the next layer
-    /// of the tree. Specifically, we can store an iterator for each layer of the tree,
-    /// and a template to generate the next layer deep in the tree.
-    ///
-    /// Pictorially, it will look something like this:
-    ///
-    /// ```
-    /// 1.
-    /// (+ A A)
-    ///
-    /// 2.
-    /// (+ A A)
-    ///    |
-    /// (+ 0 A)
-    ///
-    /// 3.
-    /// (+ A A)
-    ///    |
-    /// (+ 0 A)
-    ///    |
-    /// (+ 0 0)
-    ///
-    /// 4.
-    ///     (+ A A)
-    ///        |
-    ///     (+ 0 A)
-    ///      /  \
-    /// (+ 0 0) (+ 0 1)
-    /// ```
-    ///
-    /// We implement this by maintaining work on a stack. An item of work is a template
-    /// expression along with an iterator. Each item of work represents the set of
-    /// expressions obtained by replacing the first instance of the needle variable with
-    /// every item of the iterator. For example, `(+ 0 A), [0, 1, 2]` represents the set
-    /// of expressions `(+ 0 0) (+ 0 1) (+ 0 2)`. By moving through the iterator, you
-    /// can lazily generate nodes of the tree in the layer below the template
-    /// expression.
-    ///
-    /// In this way, we can use these items of work to keep track of where we have
-    /// explored in our tree search so far: each item of work keeps track of where we
-    /// are at each layer of the tree. Once there are no more instances of the needle
-    /// variable, we can yield the item from the iterator.
-    ///
-    /// Here is the actual algorithm:
-    /// Given an item of work, we
-    ///
-    /// 1. Try to progress the search horiztonally in the tree. We do this by checking
-    ///    the iterator associated with an item of work. If there are no items left, we
-    ///    have reached the end of this layer. We simply move onto the next item of
-    ///    work. If there are items left in the iterator,
-    /// 2. We try to spawn a deeper layer of the search. We do this by starting a new
-    ///    iterator for the child of the previous layer. If we can't go any deeper, then
-    ///    we know that we are at a leaf, and can yield this item. If we can, we make
-    ///    sure to put this item at the front of the stack so that it is processed on
-    ///    the next iterator. This results in a depth-first traversal of the tree.
-    ///
-    /// Let's walk through how this works for this workload: `(+ A A).plug(A, {0, 1, 2})`
-    ///
-    /// The stack starts off with
-    ///
-    /// ```
-    /// (+ A A), [0, 1, 2]
-    /// ```
-    ///
-    /// ```
-    /// (+ 0 A) [0, 1, 2]
-    /// (+ A A) [1, 2]
-    /// ```
-    ///
-    /// ```
-    /// (+ 0 0) [0, 1, 2]
-    /// (+ 0 A) [1, 2]
-    /// (+ A A) [1, 2]
-    /// ```
-    ///
-    /// Produced!: `(+ 0 0)`
-    ///
-    /// ```
-    /// (+ 0 A) [1, 2]
-    /// (+ A A) [1, 2]
-    /// ```
-    ///
-    /// ```
-    /// (+ 0 1) [0, 1, 2]
-    /// (+ 0 A) [2]
-    /// (+ A A) [1, 2]
-    /// ```
-    ///
-    /// Produced!: `(+ 0 1)`
-    ///
-    /// ```
-    /// (+ 0 2) [0, 1, 2]
-    /// (+ 0 A) []
-    /// (+ A A) [1, 2]
-    /// ```
-    ///
-    /// Produced!: `(+ 0 2)`
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Some((parent_sexp, mut parent_iter)) = self.stack.pop_front() {
-            // if there is juice left in the iterator
-            if let Some(next_item) = parent_iter.next() {
-                // try to go deeper one layer by replacing the first instance of the
-                // needle with the item we got from the iterator
-                if let Some(child_sexp) = parent_sexp.replace_first(&self.needle, &next_item) {
-                    // there might be more juice in the parent_iter,
-                    // so push it back on the stack so that we try
-                    // to process it again
-                    self.stack.push_front((parent_sexp, parent_iter));
-
-                    // next we want to spawn a new iterator representing one layer
-                    // deeper in the search. we want to make sure that this item
-                    // is the next item processed on the stack so that we perform
-                    // a depth-first traversal of the tree.
-                    let child_iter = (self.spawn_iterator)();
-                    self.stack.push_front((child_sexp, child_iter));
-
-                    self.next()
-                } else {
-                    // otherwise (no needle), we are at a leaf and all instances
-                    // of the needle are fully instantiated. we can yield this
-                    // item from the iterator
-                    Some(parent_sexp)
+fn write_json(out: &mut impl Write, sexp: &Sexp) -> io::Result<()> {
+    match sexp {
+        Sexp::Atom(_) | Sexp::Hole(_) => {
+            // holes keep their `?`, as when printed
+            write!(out, "\"")?;
+            for c in sexp.to_string().chars() {
+                match c {
+                    '"' => write!(out, "\\\"")?,
+                    '\\' => write!(out, "\\\\")?,
+                    c if c.is_control() => write!(out, "\\u{:04x}", c as u32)?,
+                    c => write!(out, "{c}")?,
                 }
-            } else {
-                // we are done with this layer of the tree. continue processing
-                // the next item on the stack
-                self.next()
             }
-        } else {
-            None
+            write!(out, "\"")
+        }
+        Sexp::List(list) => {
+            write!(out, "[")?;
+            for (i, el) in list.iter().enumerate() {
+                if i > 0 {
+                    write!(out, ",")?;
+                }
+                write_json(out, el)?;
+            }
+            write!(out, "]")
         }
     }
 }
 
-impl Sexp {
-    fn first(&mut self, needle: &str) -> Option<&mut Self> {
-        match self {
-            Sexp::Atom(a) if a == needle => Some(self),
-            Sexp::Atom(_) => None,
-            Sexp::List(list) => list.into_iter().find_map(|s| s.first(needle)),
+fn write_stats(out: &mut impl Write, terms: impl Iterator<Item = Sexp>) -> io::Result<()> {
+    let mut count = 0;
+    let mut ranges = [(usize::MAX, 0, 0); Metric::ALL.len()];
+    for sexp in terms {
+        count += 1;
+        for (metric, (min, max, sum)) in Metric::ALL.into_iter().zip(&mut ranges) {
+            let m = sexp.measure(metric);
+            *min = m.min(*min);
+            *max = m.max(*max);
+            *sum += m;
         }
     }
 
-    fn replace_first(&self, needle: &str, new: &Sexp) -> Option<Self> {
-        let mut copy = self.clone();
-        if let Some(ptr) = copy.first(needle) {
-            *ptr = new.clone();
-            Some(copy)
-        } else {
-            None
+    writeln!(out, "terms {count}")?;
+    if count > 0 {
+        writeln!(
+            out,
+            "{:<16}{:>8}{:>8}{:>10}",
+            "metric", "min", "max", "mean"
+        )?;
+        for (metric, (min, max, sum)) in Metric::ALL.into_iter().zip(ranges) {
+            let mean = sum as f64 / count as f64;
+            writeln!(
+                out,
+                "{:<16}{min:>8}{max:>8}{mean:>10.2}",
+                metric.to_string()
+            )?;
         }
     }
+    Ok(())
 }
 
-#[derive(PartialEq, Eq, Clone, Debug)]
-pub enum Workload {
-    Set(Vec<Sexp>),
-    Plug(Box<Self>, String, Box<Self>),
-}
+fn run(opts: Options) -> Result<(), String> {
+    let (name, src) = match opts.file.as_deref() {
+        None | Some("-") => {
+            let mut src = String::new();
+            io::stdin()
+                .read_to_string(&mut src)
+                .map_err(|e| format!("<stdin>: {e}"))?;
+            ("<stdin>", src)
+        }
+        Some(path) => (
+            path,
+            std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?,
+        ),
+    };
+    let wkld = Workload::parse(&src).map_err(|e| format!("{name}:{e}"))?;
+    for hole in wkld.unplugged_holes() {
+        eprintln!("warning: {name}: the pegs contain the hole `?{hole}`, which is never plugged");
+    }
 
-impl Workload {
-    fn plug(self, hole: &str, pegs: Self) -> Workload {
-        Workload::Plug(Box::new(self), hole.to_string(), Box::new(pegs))
+    // when nothing needs to be looked at, the count can be computed from the structure
+    if opts.count && !opts.dedup && opts.sample.is_none() && opts.shard.is_none() {
+        match wkld.count() {
+            Ok(n) => {
+                let n = n.saturating_sub(opts.offset as u128);
+                let n = opts.limit.map_or(n, |limit| n.min(limit as u128));
+                return print(|out| writeln!(out, "{n}"));
+            }
+            // too many terms to count, but more than any limit
+            Err(CountError::Overflow) => match opts.limit {
+                Some(limit) => return print(|out| writeln!(out, "{limit}")),
+                None => return Err(CountError::Overflow.to_string()),
+            },
+            Err(CountError::Unknown) => (),
+        }
     }
-}
 
-impl IntoIterator for Workload {
-    type Item = Sexp;
-    type IntoIter = Box<dyn Iterator<Item = Sexp>>;
+    let terms: Box<dyn Iterator<Item = Sexp>> = match (opts.sample, opts.shard) {
+        (Some(n), _) => {
+            let mut rng = StdRng::seed_from_u64(opts.seed);
+            let sample = wkld
+                .sample_distinct(&mut rng, n)
+                .map_err(|e| e.to_string())?;
+            Box::new(sample.into_iter())
+        }
+        (None, Some((i, n))) => wkld.shard(i, n),
+        (None, None) => Box::new(wkld.into_iter()),
+    };
+
+    let terms = match (opts.dedup, opts.vars.is_empty()) {
+        (false, _) => terms,
+        (true, true) => Dedup::Exact.apply(terms),
+        (true, false) => Dedup::Renaming(Vars::Atoms(opts.vars)).apply(terms),
+    };
+    let terms = terms
+        .skip(opts.offset)
+        .take(opts.limit.unwrap_or(usize::MAX));
 
-    fn into_iter(self) -> Self::IntoIter {
-        match self {
-            Workload::Set(v) => Box::new(v.into_iter()),
-            Workload::Plug(wkld, hole, pegs) => Box::new(
-                wkld.into_iter()
-                    .map(move |sexp| (sexp, hole.clone(), pegs.clone()))
-                    .map(|(sexp, hole, pegs)| {
-                        SexpSubstIter::new(sexp, hole, move || pegs.clone().into_iter())
-                    })
-                    .flatten(),
-            ),
+    print(|out| {
+        if opts.count {
+            writeln!(out, "{}", terms.count())
+        } else if opts.stats {
+            write_stats(out, terms)
+        } else {
+            for sexp in terms {
+                match opts.format {
+                    Format::Sexp => write!(out, "{sexp}")?,
+                    Format::Json => write_json(out, &sexp)?,
+                }
+                writeln!(out)?;
+            }
+            Ok(())
         }
+    })
+}
+
+/// Write to stdout through a buffer.
+fn print(write: impl FnOnce(&mut BufWriter<StdoutLock>) -> io::Result<()>) -> Result<(), String> {
+    let mut out = BufWriter::new(io::stdout().lock());
+    match write(&mut out).and_then(|()| out.flush()) {
+        // the reader went away (e.g. `| head`), which is not our problem
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        res => res.map_err(|e| e.to_string()),
     }
 }
 
-fn main() {
-    let v = Workload::Set(vec![
-        Sexp::Atom("0".to_string()),
-        Sexp::Atom("1".to_string()),
-        Sexp::Atom("2".to_string()),
-    ]);
-    let v0 = Workload::Set(vec![
-        Sexp::Atom("a".to_string()),
-        Sexp::Atom("b".to_string()),
-    ]);
-    let expr = Sexp::List(vec![
-        Sexp::Atom("+".to_string()),
-        Sexp::Atom("A".to_string()),
-        Sexp::Atom("B".to_string()),
-    ]);
-    let wkld = Workload::Set(vec![expr]).plug("A", v).plug("B", v0);
+fn main() -> ExitCode {
+    let res = match Options::parse(std::env::args().skip(1)) {
+        Ok(Some(opts)) => run(opts),
+        Ok(None) => print(|out| writeln!(out, "{USAGE}")),
+        Err(e) => {
+            eprintln!("error: {e}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
 
-    for v in wkld {
-        println!("recv: {v}");
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
     }
 }
@@ -0,0 +1,78 @@
+use std::{collections::HashSet, fmt::Display, str::FromStr};
+
+use crate::{Atom, Sexp};
+
+/// A numeric property of a term, used to bound and filter enumeration.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub enum Metric {
+    /// The number of nodes: every atom, hole and list counts as one.
+    Size,
+    /// The length of the longest path from the root to a leaf, counting nodes. An atom
+    /// or a hole (or an empty list) has depth `1`.
+    Depth,
+    /// The number of atoms and holes, counting repeated ones every time they appear.
+    Atoms,
+    /// The number of different atoms and holes.
+    DistinctAtoms,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 4] = [
+        Metric::Size,
+        Metric::Depth,
+        Metric::Atoms,
+        Metric::DistinctAtoms,
+    ];
+}
+
+impl Display for Metric {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Metric::Size => write!(f, "size"),
+            Metric::Depth => write!(f, "depth"),
+            Metric::Atoms => write!(f, "atoms"),
+            Metric::DistinctAtoms => write!(f, "distinct-atoms"),
+        }
+    }
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Metric::ALL
+            .into_iter()
+            .find(|m| m.to_string() == s)
+            .ok_or_else(|| format!("unknown metric `{s}`"))
+    }
+}
+
+impl<A: Atom> Sexp<A> {
+    /// Compute `metric` for this term.
+    pub fn measure(&self, metric: Metric) -> usize {
+        match (metric, self) {
+            (Metric::DistinctAtoms, _) => {
+                let mut atoms = HashSet::new();
+                self.collect_atoms(&mut atoms);
+                atoms.len()
+            }
+            (_, Sexp::Atom(_) | Sexp::Hole(_)) => 1,
+            (Metric::Size, Sexp::List(list)) => {
+                1 + list.iter().map(|s| s.measure(metric)).sum::<usize>()
+            }
+            (Metric::Depth, Sexp::List(list)) => {
+                1 + list.iter().map(|s| s.measure(metric)).max().unwrap_or(0)
+            }
+            (Metric::Atoms, Sexp::List(list)) => list.iter().map(|s| s.measure(metric)).sum(),
+        }
+    }
+
+    fn collect_atoms<'a>(&'a self, atoms: &mut HashSet<&'a Self>) {
+        match self {
+            Sexp::Atom(_) | Sexp::Hole(_) => {
+                atoms.insert(self);
+            }
+            Sexp::List(list) => list.iter().for_each(|s| s.collect_atoms(atoms)),
+        }
+    }
+}
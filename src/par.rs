@@ -0,0 +1,241 @@
+use std::{collections::VecDeque, ops::Range, sync::Arc};
+
+use rayon::iter::ParallelIterator;
+
+use crate::{Atom, Filter, Sexp, SexpSubstIter, Workload};
+
+/// The pegs that a frame of a [`SexpSubstIter`] still has to try, as a range of
+/// positions in the pegs of the plug, so that the rest of the frame can be split in two.
+#[derive(Clone)]
+pub(crate) struct PegRange<A = String> {
+    pegs: Arc<[Sexp<A>]>,
+    range: Range<usize>,
+}
+
+impl<A: Atom> PegRange<A> {
+    /// All of `pegs`.
+    pub(crate) fn new(pegs: Arc<[Sexp<A>]>) -> Self {
+        let range = 0..pegs.len();
+        PegRange { pegs, range }
+    }
+
+    /// Keep the first half of the range, and return the second half.
+    pub(crate) fn split(&mut self) -> Self {
+        let mid = self.range.start + self.range.len() / 2;
+        let rest = PegRange {
+            pegs: self.pegs.clone(),
+            range: mid..self.range.end,
+        };
+        self.range.end = mid;
+        rest
+    }
+
+    /// The peg that was taken last, if any.
+    pub(crate) fn current(&self) -> Option<Sexp<A>> {
+        let i = self.range.start.checked_sub(1)?;
+        Some(self.pegs[i].clone())
+    }
+
+    /// Drop the pegs that are left, but keep the current one.
+    pub(crate) fn clear(&mut self) {
+        self.range.end = self.range.start;
+    }
+}
+
+impl<A: Atom> Iterator for PegRange<A> {
+    type Item = Sexp<A>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.range.next().map(|i| self.pegs[i].clone())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.range.size_hint()
+    }
+}
+
+impl<A: Atom> ExactSizeIterator for PegRange<A> {}
+
+/// A [`SexpSubstIter`] over a [`PegRange`], whatever its spawning closure is.
+trait Subst<A>: Iterator<Item = Sexp<A>> + Send {
+    fn split(&mut self) -> Option<Box<dyn Subst<A>>>;
+}
+
+impl<F, A> Subst<A> for SexpSubstIter<PegRange<A>, F, A>
+where
+    F: Fn() -> PegRange<A> + Clone + Send + 'static,
+    A: Atom,
+{
+    fn split(&mut self) -> Option<Box<dyn Subst<A>>> {
+        SexpSubstIter::split(self).map(|rest| Box::new(rest) as Box<dyn Subst<A>>)
+    }
+}
+
+/// A depth-first plug, with its pegs collected up front.
+struct Plug<A> {
+    hole: A,
+    pegs: Arc<[Sexp<A>]>,
+    rightmost_first: bool,
+}
+
+impl<A: Atom> Plug<A> {
+    fn subst(&self, template: Sexp<A>) -> Box<dyn Subst<A>> {
+        let pegs = self.pegs.clone();
+        let iter = SexpSubstIter::new(template, self.hole.clone(), move || {
+            PegRange::new(pegs.clone())
+        });
+        if self.rightmost_first {
+            Box::new(iter.rightmost_first())
+        } else {
+            Box::new(iter)
+        }
+    }
+}
+
+/// Part of the enumeration of a workload, which can be split into two parts that
+/// produce its terms in the same order, one after the other.
+enum Piece<A> {
+    Terms(Vec<Sexp<A>>),
+    Subst(Box<dyn Subst<A>>),
+    Seq(VecDeque<Self>),
+    /// Every term of the first piece, as a template for the plug.
+    Plug(Box<Self>, Arc<Plug<A>>),
+    Filter(Box<Self>, Arc<Filter<A>>),
+}
+
+impl<A: Atom> Piece<A> {
+    fn new(wkld: Workload<A>) -> Self {
+        let wkld = match wkld.into_lexicographic_plug() {
+            Ok((templates, hole, pegs, rightmost_first)) => {
+                let pegs: Vec<Sexp<A>> = pegs.into_par_iter().collect();
+                let plug = Plug {
+                    hole,
+                    pegs: pegs.into(),
+                    rightmost_first,
+                };
+                return Piece::Plug(Box::new(Piece::new(templates)), Arc::new(plug));
+            }
+            Err(wkld) => wkld,
+        };
+        match wkld {
+            Workload::Set(v) => Piece::Terms(v),
+            Workload::Filter(wkld, filter) => {
+                Piece::Filter(Box::new(Piece::new(*wkld)), Arc::new(filter))
+            }
+            Workload::Append(wklds) => Piece::Seq(wklds.into_iter().map(Piece::new).collect()),
+            // fair orders are inherently sequential
+            wkld @ (Workload::Stream(_)
+            | Workload::Plug(..)
+            | Workload::PlugMany(..)
+            | Workload::Dedup(..)
+            | Workload::Interleave(_)) => Piece::Terms(wkld.into_iter().collect()),
+        }
+    }
+
+    /// Split off the second part of the terms. Returns `None` if at most one term is
+    /// left, which is what makes it cheap to expand the last template of a plug.
+    fn split(self) -> (Self, Option<Self>) {
+        match self {
+            Piece::Terms(mut v) if v.len() >= 2 => {
+                let rest = v.split_off(v.len() / 2);
+                (Piece::Terms(v), Some(Piece::Terms(rest)))
+            }
+            Piece::Subst(mut iter) => {
+                let rest = iter.split();
+                (Piece::Subst(iter), rest.map(Piece::Subst))
+            }
+            Piece::Seq(mut pieces) if pieces.len() >= 2 => {
+                let rest = pieces.split_off(pieces.len() / 2);
+                (Piece::Seq(pieces), Some(Piece::Seq(rest)))
+            }
+            Piece::Seq(mut pieces) if pieces.len() == 1 => pieces.pop_front().unwrap().split(),
+            Piece::Plug(templates, plug) => match templates.split() {
+                (templates, Some(rest)) => (
+                    Piece::Plug(Box::new(templates), plug.clone()),
+                    Some(Piece::Plug(Box::new(rest), plug)),
+                ),
+                // split the substitution tree of the last template instead
+                (templates, None) => Piece::Seq(
+                    templates
+                        .into_iter()
+                        .map(|t| Piece::Subst(plug.subst(t)))
+                        .collect(),
+                )
+                .split(),
+            },
+            Piece::Filter(piece, filter) => {
+                let (piece, rest) = piece.split();
+                (
+                    Piece::Filter(Box::new(piece), filter.clone()),
+                    rest.map(|rest| Piece::Filter(Box::new(rest), filter)),
+                )
+            }
+            piece => (piece, None),
+        }
+    }
+
+    fn into_iter(self) -> Box<dyn Iterator<Item = Sexp<A>>> {
+        match self {
+            Piece::Terms(v) => Box::new(v.into_iter()),
+            Piece::Subst(iter) => Box::new(iter),
+            Piece::Seq(pieces) => Box::new(pieces.into_iter().flat_map(Piece::into_iter)),
+            Piece::Plug(templates, plug) => {
+                Box::new(templates.into_iter().flat_map(move |t| plug.subst(t)))
+            }
+            Piece::Filter(piece, filter) => {
+                Box::new(piece.into_iter().filter(move |sexp| filter.test(sexp)))
+            }
+        }
+    }
+}
+
+impl<A: Atom> Workload<A> {
+    /// Enumerate the workload on the rayon thread pool.
+    ///
+    /// The enumeration is split recursively whenever a thread runs out of work: sets
+    /// and appends are split in half, and the substitution tree of a depth-first plug
+    /// is split at the frames of its [`SexpSubstIter`], so that even a single template
+    /// with many instances of the hole is spread over all threads.
+    ///
+    /// Collecting the terms, e.g. into a `Vec`, keeps the order of `into_iter`.
+    /// Consumers that don't care about order, like `for_each` or `reduce`, see the terms
+    /// in whatever order the threads produce them, and don't have to buffer anything.
+    ///
+    /// The pegs of every plug are collected up front, and streams, dedups, interleavings
+    /// and the other kinds of plugs (with a fair [`Traversal`], a hole that isn't
+    /// [`PlugMode::Independent`] or several holes at once) are enumerated sequentially
+    /// before being split, so all of them have to be finite.
+    ///
+    /// [`Traversal`]: crate::Traversal
+    /// [`PlugMode::Independent`]: crate::PlugMode::Independent
+    pub fn into_par_iter(self) -> impl ParallelIterator<Item = Sexp<A>> {
+        rayon::iter::split(Piece::new(self), Piece::split).flat_map_iter(Piece::into_iter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_order_of_into_iter() {
+        for src in [
+            "plug({(f ?A ?A ?A ?A ?A) x (g ?A)}, A, {0 1 2})",
+            "plug(plug({(f ?A ?B ?A) (g ?B)}, A, {?B 0 (h ?B)}), B, {0 1 2}, reverse-lex)",
+            "plug({(f ?A ?A ?A)}, A, plug({(s ?B) (t ?B ?B)}, B, {0 1}))",
+            "filter(plug({(f ?A ?A ?A ?A)}, A, {0 1 2}), contains 1)",
+            "append({a b}, plug({(f ?A ?A ?A)}, A, {0 1 2}), filter({c (d 1)}, contains 1))",
+            // enumerated sequentially
+            "plug({(f ?A ?A ?A) (g ?A)}, A, {0 1 2}, uniform)",
+            "plug({(f ?A ?B ?A)}, A, {0 1}, B, {2 3})",
+            "dedup(plug({(f ?A) (f 0)}, A, {0 1 0}))",
+            "interleave({a b c}, plug({(f ?A ?A)}, A, {0 1}))",
+            "plug(interleave({(f ?A)}, {(g ?A ?A)}), A, {0 1 2}, diagonal)",
+        ] {
+            let wkld = Workload::parse(src).unwrap();
+            let expected: Vec<Sexp> = wkld.clone().into_iter().collect();
+            let found: Vec<Sexp> = wkld.into_par_iter().collect();
+            assert_eq!(found, expected, "{src}");
+        }
+    }
+}
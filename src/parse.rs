@@ -0,0 +1,299 @@
+use std::{fmt::Display, str::FromStr};
+
+use crate::Sexp;
+
+/// Why parsing failed.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum ParseErrorKind {
+    /// The input ended where an expression was expected.
+    UnexpectedEof,
+    /// A `)` appeared without a matching `(`.
+    UnexpectedClose,
+    /// A `(` was never closed. The error points at the opening paren.
+    UnclosedList,
+    /// A complete expression was parsed, but more input followed it.
+    TrailingInput,
+    /// Any other problem, described in words.
+    Message(String),
+}
+
+/// A parse failure, located by 1-indexed line and column (counted in characters).
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Display for ParseErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseErrorKind::UnexpectedEof => write!(f, "unexpected end of input"),
+            ParseErrorKind::UnexpectedClose => write!(f, "unexpected `)`"),
+            ParseErrorKind::UnclosedList => write!(f, "unclosed `(`"),
+            ParseErrorKind::TrailingInput => write!(f, "unexpected input after expression"),
+            ParseErrorKind::Message(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.kind)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// A cursor over some source text that knows how to skip whitespace and `;` comments
+/// and read s-expressions. It keeps track of line and column so that errors can point
+/// at the offending character.
+///
+/// Atoms are maximal runs of characters that are not whitespace, `(`, `)`, `;`, or
+/// one of the reader's extra delimiters. Extra delimiters let a surrounding language
+/// embed s-expressions next to its own punctuation.
+pub(crate) struct Reader<'a> {
+    chars: Vec<char>,
+    idx: usize,
+    line: usize,
+    column: usize,
+    extra_delimiters: &'a [char],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(src: &str) -> Self {
+        Reader::with_delimiters(src, &[])
+    }
+
+    pub(crate) fn with_delimiters(src: &str, extra_delimiters: &'a [char]) -> Self {
+        Reader {
+            chars: src.chars().collect(),
+            idx: 0,
+            line: 1,
+            column: 1,
+            extra_delimiters,
+        }
+    }
+
+    pub(crate) fn peek(&self) -> Option<char> {
+        self.chars.get(self.idx).copied()
+    }
+
+    pub(crate) fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.idx += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    pub(crate) fn position(&self) -> (usize, usize) {
+        (self.line, self.column)
+    }
+
+    pub(crate) fn error(&self, kind: ParseErrorKind) -> ParseError {
+        self.error_at(self.position(), kind)
+    }
+
+    pub(crate) fn error_at(
+        &self,
+        (line, column): (usize, usize),
+        kind: ParseErrorKind,
+    ) -> ParseError {
+        ParseError { kind, line, column }
+    }
+
+    /// Skip whitespace and comments. A comment runs from `;` to the end of the line.
+    pub(crate) fn skip_trivia(&mut self) {
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() {
+                self.bump();
+            } else if c == ';' {
+                while self.peek().is_some_and(|c| c != '\n') {
+                    self.bump();
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    pub(crate) fn at_eof(&self) -> bool {
+        self.idx >= self.chars.len()
+    }
+
+    /// Consume `keyword` if it appears next as a whole word.
+    pub(crate) fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let len = keyword.chars().count();
+        let matches = self.chars[self.idx..]
+            .iter()
+            .take(len)
+            .copied()
+            .eq(keyword.chars())
+            && self
+                .chars
+                .get(self.idx + len)
+                .is_none_or(|&c| self.is_delimiter(c));
+        if matches {
+            (0..len).for_each(|_| {
+                self.bump();
+            });
+        }
+        matches
+    }
+
+    /// Consume `c` if it is the next character, or report what was expected.
+    pub(crate) fn expect(&mut self, c: char) -> Result<(), ParseError> {
+        self.skip_trivia();
+        if self.peek() == Some(c) {
+            self.bump();
+            Ok(())
+        } else {
+            Err(self.error(ParseErrorKind::Message(format!("expected `{c}`"))))
+        }
+    }
+
+    fn is_delimiter(&self, c: char) -> bool {
+        c.is_whitespace() || matches!(c, '(' | ')' | ';') || self.extra_delimiters.contains(&c)
+    }
+
+    /// Read an atom, returning `None` if the cursor isn't sitting on one.
+    pub(crate) fn atom(&mut self) -> Option<String> {
+        let mut atom = String::new();
+        while let Some(c) = self.peek().filter(|&c| !self.is_delimiter(c)) {
+            atom.push(c);
+            self.bump();
+        }
+        (!atom.is_empty()).then_some(atom)
+    }
+
+    /// Read a single s-expression, skipping any leading trivia.
+    pub(crate) fn sexp(&mut self) -> Result<Sexp, ParseError> {
+        self.skip_trivia();
+        match self.peek() {
+            None => Err(self.error(ParseErrorKind::UnexpectedEof)),
+            Some(')') => Err(self.error(ParseErrorKind::UnexpectedClose)),
+            Some('(') => {
+                let open = self.position();
+                self.bump();
+                let mut list = vec![];
+                loop {
+                    self.skip_trivia();
+                    match self.peek() {
+                        None => return Err(self.error_at(open, ParseErrorKind::UnclosedList)),
+                        Some(')') => {
+                            self.bump();
+                            return Ok(Sexp::List(list));
+                        }
+                        Some(_) => list.push(self.sexp()?),
+                    }
+                }
+            }
+            Some(c) => self
+                .atom()
+                .map(|atom| match atom.strip_prefix('?') {
+                    Some(name) if !name.is_empty() => Sexp::Hole(name.to_string()),
+                    _ => Sexp::Atom(atom),
+                })
+                .ok_or_else(|| self.error(ParseErrorKind::Message(format!("unexpected `{c}`")))),
+        }
+    }
+}
+
+impl Sexp {
+    /// Parse a single s-expression in the format that `Display` produces. Whitespace
+    /// (including newlines) may appear between tokens, and `;` starts a comment that
+    /// runs to the end of the line. An atom that starts with `?`, other than `?` itself,
+    /// is read as the hole named by the rest of it.
+    ///
+    /// For every `Sexp` whose atoms and holes are non-empty and contain no whitespace,
+    /// parens, or `;`, and whose atoms don't start with `?`, parsing is the inverse of
+    /// printing: `Sexp::parse(&s.to_string()) == Ok(s)`.
+    pub fn parse(src: &str) -> Result<Self, ParseError> {
+        let mut reader = Reader::new(src);
+        let sexp = reader.sexp()?;
+        reader.skip_trivia();
+        if reader.at_eof() {
+            Ok(sexp)
+        } else {
+            Err(reader.error(ParseErrorKind::TrailingInput))
+        }
+    }
+}
+
+impl FromStr for Sexp {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Sexp::parse(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn atom(a: &str) -> Sexp {
+        Sexp::Atom(a.to_string())
+    }
+
+    fn error(src: &str) -> (ParseErrorKind, usize, usize) {
+        let e = Sexp::parse(src).unwrap_err();
+        (e.kind, e.line, e.column)
+    }
+
+    #[test]
+    fn round_trip() {
+        let sexps = [
+            atom("x"),
+            atom("?"),
+            Sexp::Hole("A".to_string()),
+            Sexp::List(vec![]),
+            Sexp::List(vec![Sexp::List(vec![])]),
+            Sexp::List(vec![
+                atom("+"),
+                Sexp::List(vec![atom("f"), Sexp::Hole("A".to_string()), atom("1")]),
+                Sexp::List(vec![Sexp::List(vec![]), Sexp::List(vec![atom("λ")])]),
+            ]),
+        ];
+        for sexp in sexps {
+            assert_eq!(Sexp::parse(&sexp.to_string()), Ok(sexp));
+        }
+    }
+
+    #[test]
+    fn comments_and_newlines() {
+        let src = "; a comment\n(+ 1 ; another ) one\n\n   (f\tx)\n) ; trailing\n";
+        let expected = Sexp::List(vec![
+            atom("+"),
+            atom("1"),
+            Sexp::List(vec![atom("f"), atom("x")]),
+        ]);
+        assert_eq!(Sexp::parse(src), Ok(expected));
+        assert_eq!("a;b".parse(), Ok(atom("a")));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error(""), (ParseErrorKind::UnexpectedEof, 1, 1));
+        assert_eq!(
+            error("  ; nothing\n "),
+            (ParseErrorKind::UnexpectedEof, 2, 2)
+        );
+        assert_eq!(error("\n  )"), (ParseErrorKind::UnexpectedClose, 2, 3));
+        assert_eq!(error("(a\n (b) (c"), (ParseErrorKind::UnclosedList, 2, 6));
+        assert_eq!(error("(a b)\n  c"), (ParseErrorKind::TrailingInput, 2, 3));
+        // columns count characters, not bytes
+        assert_eq!(error("(λ) x"), (ParseErrorKind::TrailingInput, 1, 5));
+
+        let mut reader = Reader::with_delimiters("(a\n  {b})", &['{', '}']);
+        let e = reader.sexp().unwrap_err();
+        let kind = ParseErrorKind::Message("unexpected `{`".to_string());
+        assert_eq!((e.kind.clone(), e.line, e.column), (kind, 2, 3));
+        assert_eq!(e.to_string(), "2:3: unexpected `{`");
+    }
+}
@@ -0,0 +1,512 @@
+use itertools::Itertools;
+
+use crate::{
+    count::{checked_add, checked_mul, hole_weight, CountError, Weights},
+    workload::SharedPegs,
+    Atom, PlugMode, Sexp, SexpSubstIter, Workload,
+};
+
+/// One way in which a term comes out of a workload: the sum of the weights of the terms
+/// before it, and the subterms that fill in the holes of the enclosing plugs, in order.
+type Match<A> = (u128, Vec<(A, Sexp<A>)>);
+
+/// The enumeration from some term on, and the position within the expansion of that
+/// term by the enclosing plugs.
+type Seek<A> = (Box<dyn Iterator<Item = Sexp<A>>>, u128);
+
+impl<A: Atom> Sexp<A> {
+    /// Whether `sexp` is an expansion of this term by the enclosing plugs, i.e. whether
+    /// they are equal except where this term has a hole from `weights`. The subterms of
+    /// `sexp` in those places are pushed onto `captures`.
+    fn capture(
+        &self,
+        sexp: &Sexp<A>,
+        weights: &Weights<A>,
+        captures: &mut Vec<(A, Sexp<A>)>,
+    ) -> bool {
+        match (self, sexp) {
+            (Sexp::Hole(h), _) if hole_weight(weights, h).is_some() => {
+                captures.push((h.clone(), sexp.clone()));
+                true
+            }
+            (Sexp::List(pats), Sexp::List(list)) => {
+                pats.len() == list.len()
+                    && pats
+                        .iter()
+                        .zip(list)
+                        .all(|(pat, sexp)| pat.capture(sexp, weights, captures))
+            }
+            _ => self == sexp,
+        }
+    }
+}
+
+/// The weight of a term with these captures.
+fn captured_weight<A: Atom>(
+    captures: &[(A, Sexp<A>)],
+    weights: &Weights<A>,
+) -> Result<u128, CountError> {
+    captures.iter().try_fold(1, |acc, (hole, _)| {
+        checked_mul(acc, hole_weight(weights, hole).unwrap_or(1))
+    })
+}
+
+impl<A: Atom> Workload<A> {
+    /// The term at position `index` of the enumeration, i.e. `self.into_iter().nth(index)`.
+    ///
+    /// For workloads built from sets, appends and plugs of an independent hole with a
+    /// lexicographic traversal whose pegs don't bring the hole along again, this is
+    /// computed from the structure of the workload (and the sizes of its parts, see
+    /// [`Workload::count`]), no matter how large `index` is. Sets are indexed directly,
+    /// but making sure that the pegs don't contain the hole of their plug still looks at
+    /// every peg once per call, and so does weighing pegs that contain holes of
+    /// enclosing plugs. For anything else, it falls back to enumerating the workload.
+    pub fn get(&self, index: u128) -> Option<Sexp<A>> {
+        if self.is_indexed() {
+            if let Ok(found) = self.unrank(index, &vec![]) {
+                return found.map(|(sexp, _)| sexp);
+            }
+        }
+        usize::try_from(index)
+            .ok()
+            .and_then(|index| self.clone().into_iter().nth(index))
+    }
+
+    /// Like [`Workload::get`], for a workload that is already known to be indexed and
+    /// counted, so that nothing has to be checked again.
+    pub(crate) fn get_indexed(&self, index: u128) -> Option<Sexp<A>> {
+        self.unrank(index, &vec![])
+            .ok()
+            .flatten()
+            .map(|(sexp, _)| sexp)
+    }
+
+    /// Whether terms can be looked up by position without enumerating the workload.
+    pub(crate) fn is_indexed(&self) -> bool {
+        match self {
+            Workload::Set(_) => true,
+            Workload::Append(wklds) => wklds.iter().all(Workload::is_indexed),
+            Workload::Plug(..) => {
+                self.as_lexicographic_plug()
+                    .is_some_and(|(wkld, hole, pegs, _)| {
+                        !pegs.may_contain_hole(hole) && wkld.is_indexed() && pegs.is_indexed()
+                    })
+            }
+            Workload::PlugMany(..)
+            | Workload::Stream(_)
+            | Workload::Filter(..)
+            | Workload::Dedup(..)
+            | Workload::Interleave(_) => false,
+        }
+    }
+
+    /// The position of the first occurrence of `sexp` in the enumeration, or `None` if
+    /// the workload doesn't produce it. This is the inverse of [`Workload::get`].
+    ///
+    /// Like `get`, this works from the structure of the workload when it consists of
+    /// sets, appends and plugs of an independent hole with a lexicographic traversal, by
+    /// matching `sexp` against the templates and looking up the subterms that fill in
+    /// the holes among the pegs. For anything else, it falls back to enumerating the
+    /// workload, which won't finish for an infinite workload that doesn't contain `sexp`.
+    pub fn rank(&self, sexp: &Sexp<A>) -> Option<u128> {
+        if self.is_indexed() {
+            if let Ok(matches) = self.locate(sexp, &vec![]) {
+                return matches.into_iter().map(|(index, _)| index).min();
+            }
+        }
+        self.clone()
+            .into_iter()
+            .position(|s| s == *sexp)
+            .map(|index| index as u128)
+    }
+
+    /// Every way in which `sexp` comes out of the expansion of this workload by the
+    /// enclosing plugs, which have the holes in `weights`. This is the inverse of
+    /// [`Workload::unrank`].
+    fn locate(&self, sexp: &Sexp<A>, weights: &Weights<A>) -> Result<Vec<Match<A>>, CountError> {
+        let mut found = vec![];
+        match self {
+            Workload::Set(v) => {
+                let mut before = 0;
+                for s in v {
+                    let mut captures = vec![];
+                    if s.capture(sexp, weights, &mut captures) {
+                        found.push((before, captures));
+                    }
+                    before = checked_add(before, s.weight(weights)?)?;
+                }
+            }
+            Workload::Append(wklds) => {
+                let mut before = 0;
+                for wkld in wklds {
+                    for (index, captures) in wkld.locate(sexp, weights)? {
+                        found.push((checked_add(before, index)?, captures));
+                    }
+                    before = checked_add(before, wkld.weighted_count(weights)?)?;
+                }
+            }
+            Workload::Plug(..) => {
+                let Some((wkld, hole, pegs, from_right)) = self.as_lexicographic_plug() else {
+                    return Err(CountError::Unknown);
+                };
+                let Some(peg_weight) = pegs.peg_weight(weights)? else {
+                    return Ok(found);
+                };
+                let mut template_weights = weights.clone();
+                template_weights.push((hole, peg_weight, &PlugMode::Independent));
+
+                for (start, captures) in wkld.locate(sexp, &template_weights)? {
+                    // every way of finding the subterm in each instance of the hole among
+                    // the pegs, in the order of the instances in the template
+                    let options = captures
+                        .iter()
+                        .filter(|(h, _)| h == hole)
+                        .map(|(_, s)| pegs.locate(s, weights))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let rest = captured_weight(
+                        &captures
+                            .iter()
+                            .filter(|(h, _)| h != hole)
+                            .cloned()
+                            .collect_vec(),
+                        weights,
+                    )?;
+
+                    let choices: Vec<Vec<&Match<A>>> = if options.is_empty() {
+                        vec![vec![]]
+                    } else {
+                        options
+                            .iter()
+                            .map(|o| o.iter())
+                            .multi_cartesian_product()
+                            .collect()
+                    };
+                    for choice in choices {
+                        // undo the decoding of `unrank`, slowest instance first
+                        let mut slots = choice.clone();
+                        if from_right {
+                            slots.reverse();
+                        }
+                        let mut index = start;
+                        let mut prefix = rest;
+                        for (j, (before, peg_captures)) in slots.iter().enumerate() {
+                            let unit = (j + 1..slots.len())
+                                .try_fold(prefix, |acc, _| checked_mul(acc, peg_weight))?;
+                            index = checked_add(index, checked_mul(unit, *before)?)?;
+                            prefix = checked_mul(prefix, captured_weight(peg_captures, weights)?)?;
+                        }
+
+                        // the holes of the enclosing plugs, with those inside the pegs
+                        // spliced in where the pegs go
+                        let mut pegs_captures = choice.iter().map(|(_, c)| c);
+                        let outer = captures
+                            .iter()
+                            .flat_map(|capture| {
+                                if capture.0 == *hole {
+                                    pegs_captures.next().unwrap().clone()
+                                } else {
+                                    vec![capture.clone()]
+                                }
+                            })
+                            .collect();
+                        found.push((index, outer));
+                    }
+                }
+            }
+            Workload::PlugMany(..)
+            | Workload::Stream(_)
+            | Workload::Filter(..)
+            | Workload::Dedup(..)
+            | Workload::Interleave(_) => return Err(CountError::Unknown),
+        }
+        Ok(found)
+    }
+
+    /// Find the term whose expansion by the enclosing plugs contains position `index`,
+    /// along with the position within that expansion. Terms are weighted as in
+    /// [`Workload::weighted_count`].
+    pub(crate) fn unrank(
+        &self,
+        mut index: u128,
+        weights: &Weights<A>,
+    ) -> Result<Option<(Sexp<A>, u128)>, CountError> {
+        match self {
+            // nothing to expand, so every term takes up one position
+            Workload::Set(v) if weights.is_empty() => Ok(usize::try_from(index)
+                .ok()
+                .and_then(|index| v.get(index))
+                .map(|sexp| (sexp.clone(), 0))),
+            Workload::Set(v) => {
+                for sexp in v {
+                    let weight = sexp.weight(weights)?;
+                    if index < weight {
+                        return Ok(Some((sexp.clone(), index)));
+                    }
+                    index -= weight;
+                }
+                Ok(None)
+            }
+            Workload::Append(wklds) => {
+                for wkld in wklds {
+                    let count = wkld.weighted_count(weights)?;
+                    if index < count {
+                        return wkld.unrank(index, weights);
+                    }
+                    index -= count;
+                }
+                Ok(None)
+            }
+            Workload::Plug(..) => {
+                let Some((wkld, hole, pegs, from_right)) = self.as_lexicographic_plug() else {
+                    return Err(CountError::Unknown);
+                };
+                let Some(peg_weight) = pegs.peg_weight(weights)? else {
+                    return Ok(None);
+                };
+                let mut template_weights = weights.clone();
+                template_weights.push((hole, peg_weight, &PlugMode::Independent));
+                let Some((template, mut index)) = wkld.unrank(index, &template_weights)? else {
+                    return Ok(None);
+                };
+
+                // The expansions of the template are ordered lexicographically by the
+                // pegs, slowest instance of the hole first, and the expansion for pegs
+                // `q_1 .. q_k` takes up `rest * w(q_1) * .. * w(q_k)` positions. So
+                // with the pegs for the slower instances fixed, every choice for the
+                // next instance takes up `unit` positions per unit of its weight.
+                *template_weights.last_mut().unwrap() = (hole, 1, &PlugMode::Independent);
+                let rest = template.weight(&template_weights)?;
+                let holes = template.occurrences(hole);
+                let mut chosen = Vec::with_capacity(holes);
+                for j in 0..holes {
+                    let unit =
+                        (j + 1..holes).try_fold(rest, |acc, _| checked_mul(acc, peg_weight))?;
+                    let unit = chosen
+                        .iter()
+                        .try_fold(unit, |acc, (_, w)| checked_mul(acc, *w))?;
+                    let Some((peg, offset)) = pegs.unrank(index / unit, weights)? else {
+                        return Ok(None);
+                    };
+                    index = checked_add(index % unit, checked_mul(unit, offset)?)?;
+                    let weight = peg.weight(weights)?;
+                    chosen.push((peg, weight));
+                }
+
+                if from_right {
+                    // the last instance is the slowest one
+                    chosen.reverse();
+                }
+                let sexp = template.fill(hole, &mut chosen.iter().map(|(peg, _)| peg));
+                Ok(Some((sexp, index)))
+            }
+            Workload::PlugMany(..)
+            | Workload::Stream(_)
+            | Workload::Filter(..)
+            | Workload::Dedup(..)
+            | Workload::Interleave(_) => Err(CountError::Unknown),
+        }
+    }
+
+    /// Like [`Workload::unrank`], but instead of the term, return the enumeration from
+    /// that term on, so that the terms after it don't have to be unranked one by one.
+    /// The frames of the depth-first traversals of the plugs are set up as if they had
+    /// just produced the term.
+    pub(crate) fn seek(
+        &self,
+        mut index: u128,
+        weights: &Weights<A>,
+    ) -> Result<Option<Seek<A>>, CountError> {
+        match self {
+            Workload::Set(v) => {
+                for (j, sexp) in v.iter().enumerate() {
+                    let weight = sexp.weight(weights)?;
+                    if index < weight {
+                        let rest: Vec<_> = v[j..].to_vec();
+                        return Ok(Some((Box::new(rest.into_iter()), index)));
+                    }
+                    index -= weight;
+                }
+                Ok(None)
+            }
+            Workload::Append(wklds) => {
+                for (k, wkld) in wklds.iter().enumerate() {
+                    let count = wkld.weighted_count(weights)?;
+                    if index < count {
+                        let Some((iter, index)) = wkld.seek(index, weights)? else {
+                            return Ok(None);
+                        };
+                        let rest = wklds[k + 1..].to_vec();
+                        return Ok(Some((
+                            Box::new(iter.chain(rest.into_iter().flatten())),
+                            index,
+                        )));
+                    }
+                    index -= count;
+                }
+                Ok(None)
+            }
+            Workload::Plug(..) => {
+                let Some((wkld, hole, pegs, from_right)) = self.as_lexicographic_plug() else {
+                    return Err(CountError::Unknown);
+                };
+                let Some(peg_weight) = pegs.peg_weight(weights)? else {
+                    return Ok(None);
+                };
+                let mut template_weights = weights.clone();
+                template_weights.push((hole, peg_weight, &PlugMode::Independent));
+                let Some((mut templates, mut index)) = wkld.seek(index, &template_weights)? else {
+                    return Ok(None);
+                };
+                let template = templates.next().unwrap();
+
+                // pick the pegs as `unrank` does, but keep the rest of the pegs of every
+                // instance as the frame for that instance
+                *template_weights.last_mut().unwrap() = (hole, 1, &PlugMode::Independent);
+                let rest = template.weight(&template_weights)?;
+                let holes = template.occurrences(hole);
+                let mut frames = Vec::with_capacity(holes);
+                let mut chosen = Vec::with_capacity(holes);
+                for j in 0..holes {
+                    let unit =
+                        (j + 1..holes).try_fold(rest, |acc, _| checked_mul(acc, peg_weight))?;
+                    let unit = chosen
+                        .iter()
+                        .try_fold(unit, |acc, (_, w)| checked_mul(acc, *w))?;
+                    let Some((mut rest_pegs, offset)) = pegs.seek(index / unit, weights)? else {
+                        return Ok(None);
+                    };
+                    let peg = rest_pegs.next().unwrap();
+                    index = checked_add(index % unit, checked_mul(unit, offset)?)?;
+                    let weight = peg.weight(weights)?;
+                    frames.push((rest_pegs, Some(peg.clone())));
+                    chosen.push((peg, weight));
+                }
+
+                if from_right {
+                    chosen.reverse();
+                }
+                let sexp = template.fill(hole, &mut chosen.iter().map(|(peg, _)| peg));
+                let pegs = SharedPegs::new(pegs.clone());
+                let spawn = {
+                    let pegs = pegs.clone();
+                    move || pegs.iter()
+                };
+                let first: Box<dyn Iterator<Item = Sexp<A>>> = if holes == 0 {
+                    Box::new(std::iter::empty())
+                } else {
+                    let iter = SexpSubstIter::from_frames(
+                        template,
+                        hole.clone(),
+                        from_right,
+                        spawn,
+                        frames,
+                    )
+                    .unwrap();
+                    Box::new(iter)
+                };
+                let hole = hole.clone();
+                let later = templates.flat_map(move |template| {
+                    let pegs = pegs.clone();
+                    let iter = SexpSubstIter::new(template, hole.clone(), move || pegs.iter());
+                    if from_right {
+                        iter.rightmost_first()
+                    } else {
+                        iter
+                    }
+                });
+                Ok(Some((
+                    Box::new(std::iter::once(sexp).chain(first).chain(later)),
+                    index,
+                )))
+            }
+            Workload::PlugMany(..)
+            | Workload::Stream(_)
+            | Workload::Filter(..)
+            | Workload::Dedup(..)
+            | Workload::Interleave(_) => Err(CountError::Unknown),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Workloads that are indexed, with plugs nested in every way that `unrank` and
+    /// `locate` have to handle. Some of them produce the same term more than once.
+    const WORKLOADS: &[&str] = &[
+        "{a (b c) ?A}",
+        "plug({(+ ?A ?A)}, A, {0 1 2})",
+        "plug({(f ?A (g ?A)) x}, A, {0 1 2}, reverse-lex)",
+        // the pegs of the inner plug contain the hole of the outer one
+        "plug(plug({(f ?A ?B) (g ?B)}, A, {?B 0 (h ?B)}), B, {0 1})",
+        "plug(plug({(f ?A ?B ?A)}, A, {?B (h ?B)}, reverse-lex), B, {0 1 2})",
+        "plug(plug({(f ?A ?B)}, A, {?B 0}), B, {0 1}, reverse-lex)",
+        "append({a (p ?A)}, plug({(q ?A ?A)}, A, {0 1}), {a})",
+        "plug(append({(f ?A)}, plug({(g ?A ?B)}, B, {?A 1})), A, {0 (s ?B) 1})",
+    ];
+
+    /// Workloads that have to be enumerated to find a term by position.
+    const UNINDEXED: &[&str] = &[
+        // the pegs bring along the hole again, so the plug never runs out of terms
+        "plug({(f ?A)}, A, {0 (g ?A)})",
+        "plug({(f ?A ?A)}, A, {0 (g ?A)}, reverse-lex)",
+        "plug({(f ?A)}, A, plug({(g ?B)}, B, {0 ?A}))",
+    ];
+
+    #[test]
+    fn get_is_nth() {
+        for src in WORKLOADS {
+            let wkld = Workload::parse(src).unwrap();
+            assert!(wkld.is_indexed(), "{src}");
+            let terms: Vec<Sexp> = wkld.clone().into_iter().collect();
+            for (i, sexp) in terms.iter().enumerate() {
+                assert_eq!(wkld.get(i as u128).as_ref(), Some(sexp), "{src} {i}");
+            }
+            let count = wkld.count().unwrap();
+            assert_eq!(count, terms.len() as u128, "{src}");
+            assert_eq!(wkld.get(count), None, "{src}");
+        }
+    }
+
+    #[test]
+    fn rank_is_first_occurrence() {
+        for src in WORKLOADS {
+            let wkld = Workload::parse(src).unwrap();
+            let terms: Vec<Sexp> = wkld.clone().into_iter().collect();
+            for i in 0..terms.len() {
+                let sexp = wkld.get(i as u128).unwrap();
+                let first = terms.iter().position(|s| *s == sexp).unwrap();
+                assert_eq!(wkld.rank(&sexp), Some(first as u128), "{src} {sexp}");
+            }
+            let missing = Sexp::parse("(not produced)").unwrap();
+            assert_eq!(wkld.rank(&missing), None, "{src}");
+        }
+    }
+
+    #[test]
+    fn unindexed_is_uncounted() {
+        for src in UNINDEXED {
+            let wkld = Workload::parse(src).unwrap();
+            assert!(!wkld.is_indexed(), "{src}");
+            assert_eq!(wkld.count(), Err(CountError::Unknown), "{src}");
+        }
+    }
+
+    #[test]
+    fn unindexed_falls_back() {
+        for src in UNINDEXED {
+            let wkld = Workload::parse(src).unwrap();
+            let terms: Vec<Sexp> = wkld.clone().into_iter().take(10).collect();
+            for (i, sexp) in terms.iter().enumerate() {
+                assert_eq!(wkld.get(i as u128).as_ref(), Some(sexp), "{src} {i}");
+                let first = terms.iter().position(|s| s == sexp).unwrap();
+                assert_eq!(wkld.rank(sexp), Some(first as u128), "{src} {sexp}");
+            }
+        }
+        let wkld = Workload::parse(UNINDEXED[0]).unwrap();
+        let sexp = Sexp::parse("(f (g 0))").unwrap();
+        assert_eq!(wkld.get(1), Some(sexp.clone()));
+        assert_eq!(wkld.rank(&sexp), Some(1));
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::HashSet;
+
+use rand::{seq::SliceRandom, Rng};
+
+use crate::{count::CountError, Atom, Sexp, Workload};
+
+impl<A: Atom> Workload<A> {
+    /// Draw `n` terms uniformly at random from the enumeration, with replacement. The
+    /// result only depends on the state of `rng`, so a seeded rng gives reproducible
+    /// samples.
+    ///
+    /// When [`Workload::get`] can compute terms from the structure of the workload, this
+    /// draws positions below [`Workload::count`] and looks them up, without enumerating
+    /// anything. Otherwise it has to enumerate the workload (twice, but without keeping
+    /// more than the sample in memory), so the workload has to be finite.
+    pub fn sample<R: Rng>(&self, rng: &mut R, n: usize) -> Result<Vec<Sexp<A>>, CountError> {
+        match self.indexed_count()? {
+            Some(0) => Ok(vec![]),
+            Some(count) => Ok((0..n)
+                .filter_map(|_| self.get_indexed(rng.gen_range(0..count)))
+                .collect()),
+            None => {
+                let count = self.clone().into_iter().count();
+                if count == 0 {
+                    return Ok(vec![]);
+                }
+                // pick positions, then collect the terms at those positions in a single
+                // pass, and put them back in the order they were drawn
+                let mut picks: Vec<(usize, usize)> =
+                    (0..n).map(|draw| (rng.gen_range(0..count), draw)).collect();
+                picks.sort_unstable();
+                let mut sample = vec![None; n];
+                let mut picks = picks.into_iter().peekable();
+                for (index, sexp) in self.clone().into_iter().enumerate() {
+                    while let Some((_, draw)) = picks.next_if(|&(i, _)| i == index) {
+                        sample[draw] = Some(sexp.clone());
+                    }
+                }
+                Ok(sample.into_iter().flatten().collect())
+            }
+        }
+    }
+
+    /// Draw `n` different positions of the enumeration uniformly at random, without
+    /// replacement, and return their terms in random order. If the workload has fewer
+    /// than `n` terms, all of them are returned. Like [`Workload::sample`], the result
+    /// only depends on the state of `rng`.
+    ///
+    /// Positions are distinct, but if the workload produces a term more than once, that
+    /// term can still appear more than once in the sample.
+    pub fn sample_distinct<R: Rng>(
+        &self,
+        rng: &mut R,
+        n: usize,
+    ) -> Result<Vec<Sexp<A>>, CountError> {
+        let mut sample = match self.indexed_count()? {
+            Some(count) => {
+                // Floyd's algorithm: a uniformly random subset of size `n` from `n`
+                // draws, whatever the size of the range
+                let n = count.min(n as u128);
+                let mut seen = HashSet::new();
+                let mut indices = vec![];
+                for j in count - n..count {
+                    let i = rng.gen_range(0..=j);
+                    let i = if seen.contains(&i) { j } else { i };
+                    seen.insert(i);
+                    indices.push(i);
+                }
+                indices
+                    .into_iter()
+                    .filter_map(|i| self.get_indexed(i))
+                    .collect()
+            }
+            None => {
+                // reservoir sampling
+                let mut reservoir = Vec::with_capacity(n);
+                for (i, sexp) in self.clone().into_iter().enumerate() {
+                    if i < n {
+                        reservoir.push(sexp);
+                    } else {
+                        let j = rng.gen_range(0..=i);
+                        if j < n {
+                            reservoir[j] = sexp;
+                        }
+                    }
+                }
+                reservoir
+            }
+        };
+        sample.shuffle(rng);
+        Ok(sample)
+    }
+
+    /// The number of terms, if terms can also be looked up by position without
+    /// enumerating the workload.
+    fn indexed_count(&self) -> Result<Option<u128>, CountError> {
+        match self.count() {
+            Ok(count) if self.is_indexed() => Ok(Some(count)),
+            Ok(_) | Err(CountError::Unknown) => Ok(None),
+            Err(CountError::Overflow) => Err(CountError::Overflow),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    const WORKLOADS: &[&str] = &[
+        "plug({(f ?A ?A) x}, A, {0 1 2 3})",
+        // enumerated
+        "filter(plug({(f ?A ?A) x}, A, {0 1 2 3}), contains 1)",
+        "dedup(plug({(f ?A) (f 0)}, A, {0 1 2}))",
+    ];
+
+    #[test]
+    fn seeded_samples_repeat() {
+        for src in WORKLOADS {
+            let wkld = Workload::parse(src).unwrap();
+            let sample = |seed| wkld.sample(&mut StdRng::seed_from_u64(seed), 20).unwrap();
+            assert_eq!(sample(7), sample(7), "{src}");
+            assert_eq!(sample(7).len(), 20, "{src}");
+            let distinct = |seed| {
+                let mut rng = StdRng::seed_from_u64(seed);
+                wkld.sample_distinct(&mut rng, 3).unwrap()
+            };
+            assert_eq!(distinct(7), distinct(7), "{src}");
+        }
+    }
+
+    #[test]
+    fn samples_come_from_the_workload() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for src in WORKLOADS {
+            let wkld = Workload::parse(src).unwrap();
+            let terms: Vec<Sexp> = wkld.clone().into_iter().collect();
+            for sexp in wkld.sample(&mut rng, 50).unwrap() {
+                assert!(terms.contains(&sexp), "{src} {sexp}");
+            }
+            // the terms of these workloads are distinct, and so are the positions
+            for n in [0, 1, 5, terms.len(), terms.len() + 10] {
+                let sample = wkld.sample_distinct(&mut rng, n).unwrap();
+                assert_eq!(sample.len(), n.min(terms.len()), "{src} {n}");
+                let distinct: HashSet<&Sexp> = sample.iter().collect();
+                assert_eq!(distinct.len(), sample.len(), "{src} {n}");
+                assert!(sample.iter().all(|sexp| terms.contains(sexp)), "{src} {n}");
+            }
+        }
+    }
+
+    #[test]
+    fn empty_workloads() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for src in ["plug({(f ?A)}, A, {})", "filter({a b}, contains c)"] {
+            let wkld = Workload::parse(src).unwrap();
+            assert_eq!(wkld.sample(&mut rng, 5), Ok(vec![]), "{src}");
+            assert_eq!(wkld.sample_distinct(&mut rng, 5), Ok(vec![]), "{src}");
+        }
+    }
+}
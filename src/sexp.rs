@@ -0,0 +1,243 @@
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Display},
+    hash::Hash,
+};
+
+use crate::Symbol;
+
+/// The type of the atoms of a [`Sexp`], and of the names of its holes.
+pub trait Atom: Clone + Eq + Hash + Debug + Send + Sync + 'static {}
+
+impl Atom for Symbol {}
+
+macro_rules! atoms {
+    ($($t:ty),*) => {
+        $(impl Atom for $t {})*
+    };
+}
+
+atoms!(
+    String,
+    &'static str,
+    bool,
+    char,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize
+);
+
+/// An s-expression, whose atoms are strings unless given another [`Atom`] type.
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+pub enum Sexp<A = String> {
+    Atom(A),
+    /// A hole, which is only ever filled in by a plug of the same name, and is written
+    /// with a leading `?`, as in `(+ ?A ?A)`. An atom with the same name as a hole is
+    /// left alone.
+    Hole(A),
+    List(Vec<Self>),
+}
+
+impl<A: Display> Display for Sexp<A> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Sexp::Atom(s) => write!(f, "{s}"),
+            Sexp::Hole(s) => write!(f, "?{s}"),
+            Sexp::List(list) => {
+                write!(f, "(")?;
+                for (i, el) in list.iter().enumerate() {
+                    write!(f, "{el}")?;
+                    if i < list.len() - 1 {
+                        write!(f, " ")?;
+                    }
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl Sexp {
+    /// Replace the atoms for which `is_var` returns `true` by the holes `?0`, `?1`, ...
+    /// in the order of their first occurrence from left to right, so that two terms are
+    /// equal up to a consistent renaming of their variables exactly when their
+    /// canonical forms are equal. The result is a pattern for [`Filter::Matches`] that
+    /// matches both.
+    ///
+    /// Holes named `0`, `1`, ... would be confused with the renamed variables, so the
+    /// terms shouldn't contain any.
+    ///
+    /// [`Filter::Matches`]: crate::Filter::Matches
+    pub fn canonicalize_vars(&self, is_var: impl Fn(&str) -> bool) -> Sexp {
+        self.rename_vars(&|a| is_var(a), &|i| i.to_string(), &Clone::clone)
+    }
+}
+
+impl<A: Atom> Sexp<A> {
+    /// Replace the atoms for which `is_var` returns `true` by the holes `var(i)`, where
+    /// `i` numbers the variables in the order of their first occurrence, and rename the
+    /// other atoms and the holes with `atom`.
+    pub(crate) fn rename_vars<B>(
+        &self,
+        is_var: &impl Fn(&A) -> bool,
+        var: &impl Fn(usize) -> B,
+        atom: &impl Fn(&A) -> B,
+    ) -> Sexp<B> {
+        self.rename_vars_with(is_var, var, atom, &mut HashMap::new())
+    }
+
+    fn rename_vars_with<'a, B>(
+        &'a self,
+        is_var: &impl Fn(&A) -> bool,
+        var: &impl Fn(usize) -> B,
+        atom: &impl Fn(&A) -> B,
+        names: &mut HashMap<&'a A, usize>,
+    ) -> Sexp<B> {
+        match self {
+            Sexp::Atom(a) if is_var(a) => {
+                let next = names.len();
+                Sexp::Hole(var(*names.entry(a).or_insert(next)))
+            }
+            Sexp::Atom(a) => Sexp::Atom(atom(a)),
+            Sexp::Hole(h) => Sexp::Hole(atom(h)),
+            Sexp::List(list) => Sexp::List(
+                list.iter()
+                    .map(|s| s.rename_vars_with(is_var, var, atom, names))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// The paths to the instances of the hole `needle`, from left to right. A path lists
+    /// the positions of the subterms to descend into, starting from the root.
+    pub(crate) fn paths(&self, needle: &A) -> Vec<Vec<usize>> {
+        match self {
+            Sexp::Hole(h) if h == needle => vec![vec![]],
+            Sexp::Atom(_) | Sexp::Hole(_) => vec![],
+            Sexp::List(list) => list
+                .iter()
+                .enumerate()
+                .flat_map(|(i, s)| {
+                    s.paths(needle).into_iter().map(move |mut path| {
+                        path.insert(0, i);
+                        path
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    /// The subterm at `path`, as returned by [`Sexp::paths`].
+    pub(crate) fn at_mut(&mut self, path: &[usize]) -> &mut Self {
+        path.iter().fold(self, |sexp, &i| match sexp {
+            Sexp::List(list) => &mut list[i],
+            Sexp::Atom(_) | Sexp::Hole(_) => panic!("path goes through an atom"),
+        })
+    }
+
+    /// The number of instances of the hole `needle`.
+    pub(crate) fn occurrences(&self, needle: &A) -> usize {
+        match self {
+            Sexp::Hole(h) => usize::from(h == needle),
+            Sexp::Atom(_) => 0,
+            Sexp::List(list) => list.iter().map(|s| s.occurrences(needle)).sum(),
+        }
+    }
+
+    /// Whether the atom `atom` appears anywhere in the term.
+    pub(crate) fn contains_atom(&self, atom: &A) -> bool {
+        match self {
+            Sexp::Atom(a) => a == atom,
+            Sexp::Hole(_) => false,
+            Sexp::List(list) => list.iter().any(|s| s.contains_atom(atom)),
+        }
+    }
+
+    /// The names of the holes in the term, from left to right, with repetitions.
+    pub(crate) fn holes(&self) -> Vec<&A> {
+        match self {
+            Sexp::Hole(h) => vec![h],
+            Sexp::Atom(_) => vec![],
+            Sexp::List(list) => list.iter().flat_map(Sexp::holes).collect(),
+        }
+    }
+
+    /// Replace the instances of the hole `needle`, from left to right, with successive
+    /// items of `pegs`. Instances left over once `pegs` runs dry are kept as they are.
+    pub(crate) fn fill<'a>(&self, needle: &A, pegs: &mut impl Iterator<Item = &'a Self>) -> Self {
+        match self {
+            Sexp::Hole(h) if h == needle => pegs.next().cloned().unwrap_or_else(|| self.clone()),
+            Sexp::Atom(_) | Sexp::Hole(_) => self.clone(),
+            Sexp::List(list) => Sexp::List(list.iter().map(|s| s.fill(needle, pegs)).collect()),
+        }
+    }
+
+    /// For every hole that is one of `needles`, from left to right, the index of that
+    /// needle.
+    pub(crate) fn instances(&self, needles: &[A]) -> Vec<usize> {
+        match self {
+            Sexp::Hole(h) => needles.iter().position(|n| n == h).into_iter().collect(),
+            Sexp::Atom(_) => vec![],
+            Sexp::List(list) => list.iter().flat_map(|s| s.instances(needles)).collect(),
+        }
+    }
+
+    /// For every instance of `needles`, in the order of [`Sexp::instances`], the
+    /// position of the previous instance of the same needle among the arguments of the
+    /// same list, if the head of that list is one of `ops`.
+    pub(crate) fn previous_arguments(&self, needles: &[A], ops: &[A]) -> Vec<Option<usize>> {
+        let mut previous = vec![];
+        self.push_previous_arguments(needles, ops, &mut previous);
+        previous
+    }
+
+    fn push_previous_arguments(&self, needles: &[A], ops: &[A], previous: &mut Vec<Option<usize>>) {
+        match self {
+            Sexp::Hole(h) if needles.contains(h) => previous.push(None),
+            Sexp::Atom(_) | Sexp::Hole(_) => {}
+            Sexp::List(list) => {
+                let commutative = matches!(list.first(), Some(Sexp::Atom(op)) if ops.contains(op));
+                // the last instance of every needle among the arguments so far
+                let mut last = vec![None; needles.len()];
+                for (i, s) in list.iter().enumerate() {
+                    match needles
+                        .iter()
+                        .position(|n| matches!(s, Sexp::Hole(h) if h == n))
+                    {
+                        Some(k) if commutative && i > 0 => {
+                            previous.push(last[k]);
+                            last[k] = Some(previous.len() - 1);
+                        }
+                        _ => s.push_previous_arguments(needles, ops, previous),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Like [`Sexp::fill`], but for the instances of any of `needles`.
+    pub(crate) fn fill_any<'a>(
+        &self,
+        needles: &[A],
+        pegs: &mut impl Iterator<Item = &'a Self>,
+    ) -> Self {
+        match self {
+            Sexp::Hole(h) if needles.contains(h) => {
+                pegs.next().cloned().unwrap_or_else(|| self.clone())
+            }
+            Sexp::Atom(_) | Sexp::Hole(_) => self.clone(),
+            Sexp::List(list) => {
+                Sexp::List(list.iter().map(|s| s.fill_any(needles, pegs)).collect())
+            }
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use crate::{Atom, Sexp, Workload};
+
+impl<A: Atom> Workload<A> {
+    /// The `i`-th of `n` disjoint slices of the enumeration. Together, the shards
+    /// `0..n` produce exactly the terms of `self.into_iter()`, so they can be
+    /// enumerated by separate workers that only need to agree on `n`.
+    ///
+    /// When [`Workload::get`] can compute terms from the structure of the workload, the
+    /// shards are contiguous runs of positions whose lengths differ by at most one, and
+    /// each shard only computes its own terms: it looks up its first term by position,
+    /// and enumerates the rest from there. Otherwise every shard enumerates the
+    /// whole workload and keeps every `n`-th term, starting at position `i`, which
+    /// balances the output but not the work of enumerating.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `i` is not less than `n`.
+    pub fn shard(self, i: usize, n: usize) -> Box<dyn Iterator<Item = Sexp<A>>> {
+        assert!(i < n, "shard {i} out of range for {n} shards");
+        match self.count() {
+            Ok(count) if self.is_indexed() => {
+                // positions `count * i / n ..`, computed without overflowing
+                let (i, n) = (i as u128, n as u128);
+                let bound = |i: u128| count / n * i + count % n * i / n;
+                let (start, end) = (bound(i), bound(i + 1));
+                match self.seek(start, &vec![]) {
+                    Ok(Some((terms, _))) => {
+                        let len = usize::try_from(end - start).unwrap_or(usize::MAX);
+                        Box::new(terms.take(len))
+                    }
+                    _ => Box::new(std::iter::empty()),
+                }
+            }
+            _ => Box::new(self.into_iter().skip(i).step_by(n)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shards_make_up_the_enumeration() {
+        for src in [
+            "plug({(+ ?A ?A) x}, A, {0 1 2})",
+            "plug(append({a (p ?A)}, plug({(q ?A ?A ?B)}, A, {0 1}, reverse-lex)), B, {b ?A})",
+            "plug(plug({(f ?A ?B) (g ?B)}, A, {?B 0 (h ?B)}), B, {0 1})",
+            "plug(plug({(f ?A ?B ?A)}, A, {?B (h ?B)}, reverse-lex), B, {0 1 2})",
+            // enumerated by every shard
+            "filter(plug({(+ ?A ?A)}, A, {0 1 2}), contains 1)",
+            "plug({(+ ?A ?A)}, A, {0 1 2}, diagonal)",
+        ] {
+            let wkld = Workload::parse(src).unwrap();
+            let terms: Vec<Sexp> = wkld.clone().into_iter().collect();
+            for n in 1..=terms.len() + 1 {
+                let shards: Vec<Sexp> = (0..n).flat_map(|i| wkld.clone().shard(i, n)).collect();
+                let mut expected = terms.clone();
+                if !wkld.is_indexed() {
+                    // every `n`-th term, starting at the shard
+                    expected = (0..n)
+                        .flat_map(|i| terms.iter().skip(i).step_by(n).cloned())
+                        .collect();
+                }
+                assert_eq!(shards, expected, "{src} {n}");
+            }
+        }
+    }
+}
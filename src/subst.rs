@@ -0,0 +1,582 @@
+use crate::{par::PegRange, Atom, Sexp};
+
+/// The position of a subterm, as the indices of the lists to descend into from the
+/// root.
+type Path = Vec<usize>;
+
+/// An instance of the needle that is being filled in with pegs.
+#[derive(Debug, Clone)]
+struct Frame<I> {
+    /// The pegs the instance has left to try.
+    pegs: I,
+    /// Where the instance is, or `None` for a leaf: a term without instances left,
+    /// which is produced once if there is a peg at all.
+    path: Option<Path>,
+    /// The number of pending instances, once this one was taken off.
+    base: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct SexpSubstIter<I, F, A = String>
+where
+    I: Iterator<Item = Sexp<A>>,
+    F: Fn() -> I,
+{
+    needle: A,
+    from_right: bool,
+    spawn_iterator: F,
+    template: Sexp<A>,
+    /// The term being filled in, which every frame writes its peg into.
+    buffer: Sexp<A>,
+    /// The instances of the needle in `buffer` that no frame has taken yet, with the
+    /// next one to fill in last.
+    pending: Vec<Path>,
+    /// The frames of the traversal from the root down.
+    frames: Vec<Frame<I>>,
+}
+
+impl<I, F, A> SexpSubstIter<I, F, A>
+where
+    I: Iterator<Item = Sexp<A>>,
+    F: Fn() -> I,
+    A: Atom,
+{
+    pub(crate) fn new(inital_sexp: Sexp<A>, needle: A, spawn_iterator: F) -> Self {
+        let mut iter = SexpSubstIter {
+            needle,
+            from_right: false,
+            spawn_iterator,
+            buffer: inital_sexp.clone(),
+            template: inital_sexp,
+            pending: vec![],
+            frames: vec![],
+        };
+        iter.restart();
+        iter
+    }
+
+    /// Pick up a traversal from the frames of an earlier one, listed from the root
+    /// down: for every frame, the pegs it has left, and the peg it was last filled in
+    /// with, if any. Returns `None` if the frames don't fit the template, i.e. if there
+    /// are more frames than instances of the needle, or a frame other than the last
+    /// hasn't been filled in.
+    pub(crate) fn from_frames(
+        template: Sexp<A>,
+        needle: A,
+        from_right: bool,
+        spawn_iterator: F,
+        frames: Vec<(I, Option<Sexp<A>>)>,
+    ) -> Option<Self> {
+        let mut iter = SexpSubstIter {
+            needle,
+            from_right,
+            spawn_iterator,
+            buffer: template.clone(),
+            template,
+            pending: vec![],
+            frames: vec![],
+        };
+        iter.push_paths(vec![], &iter.template.clone());
+        let count = frames.len();
+        for (j, (pegs, peg)) in frames.into_iter().enumerate() {
+            let path = iter.pending.pop();
+            if path.is_none() && (j > 0 || peg.is_some()) {
+                return None;
+            }
+            iter.frames.push(Frame {
+                pegs,
+                path,
+                base: iter.pending.len(),
+            });
+            match peg {
+                Some(peg) => iter.fill(peg),
+                None if j + 1 < count => return None,
+                None => {}
+            }
+        }
+        Some(iter)
+    }
+
+    /// The template being filled in, or `None` once the traversal is done.
+    pub(crate) fn template(&self) -> Option<&Sexp<A>> {
+        (!self.frames.is_empty()).then_some(&self.template)
+    }
+
+    /// The pegs every frame of the traversal has left, from the root down.
+    pub(crate) fn pegs(&self) -> impl Iterator<Item = &I> {
+        self.frames.iter().map(|frame| &frame.pegs)
+    }
+
+    /// Fill in the instances of the needle from right to left instead, so that the
+    /// first instance varies fastest.
+    pub(crate) fn rightmost_first(mut self) -> Self {
+        self.from_right = true;
+        self.restart();
+        self
+    }
+
+    /// Start over with a single frame, for the next instance of the needle in the
+    /// template.
+    fn restart(&mut self) {
+        self.buffer = self.template.clone();
+        self.pending.clear();
+        self.push_paths(vec![], &self.template.clone());
+        let pegs = (self.spawn_iterator)();
+        self.frames = vec![Frame {
+            pegs,
+            path: self.pending.pop(),
+            base: self.pending.len(),
+        }];
+    }
+
+    /// Add the instances of the needle in `sexp`, which is at `prefix`, to the pending
+    /// ones. They come before the ones that are pending already: the instances left
+    /// are all after (or before, from the right) the one `sexp` fills in.
+    fn push_paths(&mut self, prefix: Path, sexp: &Sexp<A>) {
+        let paths = sexp.paths(&self.needle).into_iter().map(|path| {
+            let mut full = prefix.clone();
+            full.extend(path);
+            full
+        });
+        if self.from_right {
+            self.pending.extend(paths);
+        } else {
+            let start = self.pending.len();
+            self.pending.extend(paths);
+            self.pending[start..].reverse();
+        }
+    }
+
+    /// Write `peg` into the instance of the last frame, in place of whatever it was
+    /// filled in with before.
+    fn fill(&mut self, peg: Sexp<A>) {
+        let frame = self.frames.last().unwrap();
+        let path = frame.path.clone().unwrap();
+        self.pending.truncate(frame.base);
+        self.push_paths(path.clone(), &peg);
+        *self.buffer.at_mut(&path) = peg;
+    }
+
+    /// Put a frame for the next pending instance on top, or a leaf frame if there is
+    /// none.
+    fn push_frame(&mut self) {
+        let pegs = (self.spawn_iterator)();
+        self.frames.push(Frame {
+            pegs,
+            path: self.pending.pop(),
+            base: self.pending.len(),
+        });
+    }
+}
+
+impl<F, A> SexpSubstIter<PegRange<A>, F, A>
+where
+    F: Fn() -> PegRange<A> + Clone,
+    A: Atom,
+{
+    /// Split off the terms that this would produce last, so that they can be produced
+    /// elsewhere. Afterwards, this produces the terms before those of the returned
+    /// iterator, and together they produce exactly what this would have. Returns
+    /// `None` if at most one term is left.
+    ///
+    /// The frames are split at the one closest to the root that still has two pegs
+    /// left to try, because those pegs stand for the largest subtrees: this keeps the
+    /// first half of them, and hands over the second half along with the frames above
+    /// it. If there is no such frame, the frame closest to the root with pegs left is
+    /// handed over as a whole, as long as there is another one below it.
+    pub(crate) fn split(&mut self) -> Option<Self> {
+        // with a single peg left at the root, move on to the frame below it
+        while self.frames.len() == 1 && self.frames[0].pegs.len() == 1 {
+            // that's a leaf, which is the only term left
+            self.frames[0].path.as_ref()?;
+            let peg = self.frames[0].pegs.next().unwrap();
+            self.fill(peg);
+            self.push_frame();
+        }
+
+        let (k, pegs, peg) = match self
+            .frames
+            .iter()
+            .position(|frame| frame.pegs.len() >= 2 && frame.path.is_some())
+        {
+            Some(k) => (k, self.frames[k].pegs.split(), None),
+            None => {
+                let mut open = (0..self.frames.len()).filter(|&j| self.frames[j].pegs.len() > 0);
+                let k = open.next()?;
+                open.next()?;
+                let pegs = self.frames[k].pegs.clone();
+                self.frames[k].pegs.clear();
+                let peg = pegs.current();
+                (k, pegs, peg)
+            }
+        };
+        // the frames above hand over their pegs as well
+        let mut frames = vec![];
+        for frame in &mut self.frames[..k] {
+            frames.push((frame.pegs.clone(), frame.pegs.current()));
+            frame.pegs.clear();
+        }
+        frames.push((pegs, peg));
+        SexpSubstIter::from_frames(
+            self.template.clone(),
+            self.needle.clone(),
+            self.from_right,
+            self.spawn_iterator.clone(),
+            frames,
+        )
+    }
+}
+
+impl<I, F, A> Iterator for SexpSubstIter<I, F, A>
+where
+    I: Iterator<Item = Sexp<A>>,
+    F: Fn() -> I,
+    A: Atom,
+{
+    type Item = Sexp<A>;
+
+    /// Intuitively, the thing that we want to do is perform a traversal of the leaves
+    /// of the following tree. Each level of the tree represents substituting the hole
+    /// (`?A` in this case), with every instance of some other iterator.
+    ///
+    /// ```text
+    ///            (+ ?A ?A)
+    ///           /         \
+    ///    (+ 0 ?A)        (+ 1 ?A)
+    ///       / \             / \
+    /// (+ 0 0) (+ 0 1) (+ 1 0) (+ 1 1)
+    /// ```
+    ///
+    /// The thing that makes it tricky to write this traversal in a lazy way is that we
+    /// don't know what this tree will look like up-front; it's lazily produced by
+    /// another iterator filling in the values of `?A`.
+    ///
+    /// The trick is that we can use a stack to represent where we are in this tree
+    /// traversal, making sure that we have enough information to unfold the next layer
+    /// of the tree. Specifically, we store a frame for each layer of the tree, with the
+    /// iterator of the pegs left for that layer, and the position of the instance of
+    /// the needle that the layer fills in.
+    ///
+    /// The nodes of the tree are never built as separate terms: there is a single
+    /// buffer, and every frame writes its peg straight into its position. Going back
+    /// up the tree doesn't undo anything, because a frame only ever moves on to its
+    /// next peg, which overwrites the previous one, and the frames below it then fill
+    /// in everything after it again. The positions of the instances are found once per
+    /// template, and once per peg for the instances inside the peg, which are filled
+    /// in like the ones of the template, before the instances that come after the peg.
+    ///
+    /// Let's walk through how this works for `(+ ?A ?A)` plugged with `{0 1 2}`.
+    /// The frames start off with one for the first `A`, and the second `A` pending:
+    ///
+    /// ```text
+    /// (+ ?A ?A)   first A: [0, 1, 2]
+    /// ```
+    ///
+    /// The first frame takes `0`, after which the second `A` is next, so we push a
+    /// frame for it, which takes `0` as well. There is nothing left to fill in, so
+    /// this is a leaf:
+    ///
+    /// ```text
+    /// (+ 0 0)   first A: [1, 2], second A: [1, 2]
+    /// ```
+    ///
+    /// Produced!: `(+ 0 0)`
+    ///
+    /// The last frame moves on to its next peg:
+    ///
+    /// ```text
+    /// (+ 0 1)   first A: [1, 2], second A: [2]
+    /// ```
+    ///
+    /// Produced!: `(+ 0 1)`
+    ///
+    /// ```text
+    /// (+ 0 2)   first A: [1, 2], second A: []
+    /// ```
+    ///
+    /// Produced!: `(+ 0 2)`
+    ///
+    /// Now the last frame has run out of pegs, so we pop it, which makes the second
+    /// `A` pending again, and move on to the next peg of the first frame:
+    ///
+    /// ```text
+    /// (+ 1 2)   first A: [2]
+    /// ```
+    ///
+    /// which pushes a fresh frame for the second `A`, and so on.
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_ref().cloned()
+    }
+}
+
+impl<I, F, A> SexpSubstIter<I, F, A>
+where
+    I: Iterator<Item = Sexp<A>>,
+    F: Fn() -> I,
+    A: Atom,
+{
+    /// Like `next`, but lends the term out of the buffer instead of copying it. The
+    /// reference is only valid until the next call.
+    pub(crate) fn next_ref(&mut self) -> Option<&Sexp<A>> {
+        loop {
+            let frame = self.frames.last_mut()?;
+            let Some(peg) = frame.pegs.next() else {
+                // we are done with this layer of the tree: the instance is pending
+                // again, and the layer above moves on
+                let frame = self.frames.pop().unwrap();
+                self.pending.truncate(frame.base);
+                self.pending.extend(frame.path);
+                continue;
+            };
+            if frame.path.is_none() {
+                self.frames.pop();
+                return Some(&self.buffer);
+            }
+            self.fill(peg);
+            if self.pending.is_empty() {
+                // all instances of the needle are fully instantiated
+                return Some(&self.buffer);
+            }
+            // go one layer deeper, which is the next layer processed so that we
+            // perform a depth-first traversal of the tree
+            self.push_frame();
+        }
+    }
+}
+
+/// Fills in the instances of several holes at once, each with the pegs for that hole.
+/// Only the instances in the template are filled in: the pegs aren't searched for
+/// holes. Every combination of pegs is produced once, ordered lexicographically by the
+/// instances from left to right, so the last instance varies fastest.
+///
+/// An instance can also be bound to an earlier one, so that it only tries the pegs
+/// from the position of the peg in the earlier instance onwards. This is how
+/// [`PlugMode::Commutative`] skips the orderings of arguments it has already produced.
+///
+/// [`PlugMode::Commutative`]: crate::PlugMode::Commutative
+pub struct SexpPlugManyIter<I, F, A = String>
+where
+    I: Iterator<Item = Sexp<A>>,
+    F: Fn(usize) -> I,
+    A: Atom,
+{
+    template: Sexp<A>,
+    needles: Vec<A>,
+    spawn_iterator: F,
+    /// The needle of every instance in the template, from left to right.
+    instances: Vec<usize>,
+    /// For every instance, the earlier instance whose peg it can't come before.
+    bounds: Vec<Option<usize>>,
+    /// For every instance, the pegs it has left to try, and the one it is filled with
+    /// with its position.
+    frames: Vec<(I, Sexp<A>, usize)>,
+    started: bool,
+}
+
+impl<I, F, A> SexpPlugManyIter<I, F, A>
+where
+    I: Iterator<Item = Sexp<A>>,
+    F: Fn(usize) -> I,
+    A: Atom,
+{
+    /// `spawn_iterator(k)` produces the pegs for `needles[k]`.
+    pub(crate) fn new(template: Sexp<A>, needles: Vec<A>, spawn_iterator: F) -> Self {
+        let instances = template.instances(&needles);
+        SexpPlugManyIter {
+            bounds: vec![None; instances.len()],
+            instances,
+            template,
+            needles,
+            spawn_iterator,
+            frames: vec![],
+            started: false,
+        }
+    }
+
+    /// Only fill in the instances among the arguments of a list whose head is one of
+    /// `ops` with pegs in the order they come in, from left to right, for every needle.
+    pub(crate) fn commutative(mut self, ops: &[A]) -> Self {
+        self.bounds = self.template.previous_arguments(&self.needles, ops);
+        self
+    }
+
+    /// Start the instances from `from` onwards over with their first pegs.
+    fn restart(&mut self, from: usize) -> Option<()> {
+        self.frames.truncate(from);
+        for i in from..self.instances.len() {
+            let mut pegs = (self.spawn_iterator)(self.instances[i]);
+            let start = self.bounds[i].map_or(0, |j| self.frames[j].2);
+            let peg = pegs.nth(start)?;
+            self.frames.push((pegs, peg, start));
+        }
+        Some(())
+    }
+}
+
+impl<I, F, A> Iterator for SexpPlugManyIter<I, F, A>
+where
+    I: Iterator<Item = Sexp<A>>,
+    F: Fn(usize) -> I,
+    A: Atom,
+{
+    type Item = Sexp<A>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.started {
+            self.started = true;
+            // like plugging the holes one after the other, nothing comes out if one of
+            // them has no pegs, whether the template contains it or not
+            if (0..self.needles.len()).any(|k| (self.spawn_iterator)(k).next().is_none()) {
+                return None;
+            }
+            self.restart(0)?;
+        } else {
+            // advance the last instance that has pegs left, and start over the ones
+            // after it
+            let i = loop {
+                let (pegs, peg, position) = self.frames.last_mut()?;
+                match pegs.next() {
+                    Some(next) => {
+                        *peg = next;
+                        *position += 1;
+                        break self.frames.len();
+                    }
+                    None => {
+                        self.frames.pop();
+                    }
+                }
+            };
+            self.restart(i)?;
+        }
+        Some(self.template.fill_any(
+            &self.needles,
+            &mut self.frames.iter().map(|(_, peg, _)| peg),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::Workload;
+
+    /// The items of a list written without its parens.
+    fn sexps(src: &str) -> Vec<Sexp> {
+        match Sexp::parse(&format!("({src})")).unwrap() {
+            Sexp::List(list) => list,
+            Sexp::Atom(_) | Sexp::Hole(_) => unreachable!(),
+        }
+    }
+
+    fn subst(template: &str, pegs: &str) -> SexpSubstIter<PegRange, impl Fn() -> PegRange + Clone> {
+        let pegs: Arc<[Sexp]> = sexps(pegs).into();
+        let template = Sexp::parse(template).unwrap();
+        SexpSubstIter::new(template, "A".to_string(), move || {
+            PegRange::new(pegs.clone())
+        })
+    }
+
+    /// Split `iter` over and over, and put the terms of the parts back together.
+    fn split_all<F>(mut iter: SexpSubstIter<PegRange, F>, depth: usize) -> Vec<Sexp>
+    where
+        F: Fn() -> PegRange + Clone,
+    {
+        match iter.split() {
+            Some(rest) if depth > 0 => {
+                let mut terms = split_all(iter, depth - 1);
+                terms.extend(split_all(rest, depth - 1));
+                terms
+            }
+            Some(rest) => iter.chain(rest).collect(),
+            None => iter.collect(),
+        }
+    }
+
+    #[test]
+    fn walkthrough() {
+        let terms: Vec<Sexp> = subst("(+ ?A ?A)", "0 1 2").collect();
+        let expected = "(+ 0 0) (+ 0 1) (+ 0 2) (+ 1 0) (+ 1 1) (+ 1 2) (+ 2 0) (+ 2 1) (+ 2 2)";
+        assert_eq!(terms, sexps(expected));
+    }
+
+    #[test]
+    fn rightmost_first() {
+        let terms: Vec<Sexp> = subst("(+ ?A (f ?A))", "0 1 2").rightmost_first().collect();
+        let expected = "(+ 0 (f 0)) (+ 1 (f 0)) (+ 2 (f 0)) (+ 0 (f 1)) (+ 1 (f 1)) (+ 2 (f 1)) \
+                        (+ 0 (f 2)) (+ 1 (f 2)) (+ 2 (f 2))";
+        assert_eq!(terms, sexps(expected));
+    }
+
+    #[test]
+    fn template_without_the_hole() {
+        assert_eq!(
+            subst("(+ x ?B)", "0 1").collect::<Vec<_>>(),
+            sexps("(+ x ?B)")
+        );
+        assert_eq!(subst("(+ x ?A)", "").count(), 0);
+        assert_eq!(subst("x", "").count(), 0);
+    }
+
+    #[test]
+    fn next_ref_lends_the_same_terms() {
+        let mut iter = subst("(f ?A (g ?A) ?A)", "0 (h 1) 2");
+        let mut lent = vec![];
+        while let Some(sexp) = iter.next_ref() {
+            lent.push(sexp.clone());
+        }
+        assert_eq!(
+            lent,
+            subst("(f ?A (g ?A) ?A)", "0 (h 1) 2").collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn pegs_with_the_hole_of_an_enclosing_plug() {
+        let wkld = Workload::parse("plug(plug({(f ?A ?A)}, A, {0 (g ?B)}), B, {x y})").unwrap();
+        let expected = "(f 0 0) (f 0 (g x)) (f 0 (g y)) (f (g x) 0) (f (g y) 0) \
+                        (f (g x) (g x)) (f (g x) (g y)) (f (g y) (g x)) (f (g y) (g y))";
+        assert_eq!(wkld.into_iter().collect::<Vec<_>>(), sexps(expected));
+
+        let wkld = Workload::parse("plug(plug({(f ?A ?A)}, A, {0 (g ?B)}), B, {x y}, reverse-lex)")
+            .unwrap();
+        let expected = "(f 0 0) (f 0 (g x)) (f 0 (g y)) (f (g x) 0) (f (g y) 0) \
+                        (f (g x) (g x)) (f (g y) (g x)) (f (g x) (g y)) (f (g y) (g y))";
+        assert_eq!(wkld.into_iter().collect::<Vec<_>>(), sexps(expected));
+    }
+
+    #[test]
+    fn split_keeps_the_order() {
+        let cases = [
+            ("(+ ?A ?A)", "0 1 2"),
+            ("(f ?A (g ?A ?A) ?A)", "0 1 2 3"),
+            ("(f ?A ?A)", "0"),
+            ("(f ?A)", "0 1"),
+            ("x", "0 1 2"),
+            ("(f ?A ?A ?A)", ""),
+        ];
+        for (template, pegs) in cases {
+            for from_right in [false, true] {
+                let iter = || {
+                    let iter = subst(template, pegs);
+                    if from_right {
+                        iter.rightmost_first()
+                    } else {
+                        iter
+                    }
+                };
+                let all: Vec<Sexp> = iter().collect();
+                // split after every number of terms taken, as deep as it goes
+                for taken in 0..=all.len() {
+                    for depth in [0, 1, 3, usize::MAX] {
+                        let mut iter = iter();
+                        let mut terms: Vec<Sexp> = iter.by_ref().take(taken).collect();
+                        terms.extend(split_all(iter, depth));
+                        assert_eq!(terms, all, "{template} {pegs} {from_right} {taken} {depth}");
+                    }
+                }
+            }
+        }
+    }
+}
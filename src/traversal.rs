@@ -0,0 +1,547 @@
+use std::{fmt::Display, str::FromStr};
+
+use crate::{Atom, Sexp};
+
+/// The order in which a [`Workload::Plug`] visits the ways of filling in its hole.
+///
+/// A template with `k` instances of the hole, plugged with pegs `p0, p1, ...`, produces
+/// one term per `k`-tuple of peg indices. Each traversal is a different order on those
+/// tuples; all of them produce the same set of terms, as long as no peg contains the
+/// hole that it is plugged into.
+///
+/// When a peg does contain that hole, the traversals disagree. The lexicographic ones
+/// fill in the instances of the hole that the peg brings along too, so they never stop
+/// on such a peg. `BreadthFirst` and `Diagonal` fill in each template once, and leave
+/// the instances that come with the pegs in their terms.
+///
+/// [`Workload::Plug`]: crate::Workload::Plug
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Default)]
+pub enum Traversal {
+    /// Lexicographic order, via the depth-first search of [`SexpSubstIter`]. The last
+    /// instance of the hole varies fastest, and templates are expanded one after the
+    /// other. If the pegs are infinite, only the first peg is ever put in the first
+    /// instance of the hole.
+    ///
+    /// [`SexpSubstIter`]: crate::SexpSubstIter
+    #[default]
+    DepthFirst,
+    /// Like `DepthFirst`, but the instances of the hole are filled in from right to
+    /// left, so the first instance varies fastest.
+    ReverseLexicographic,
+    /// Breadth-first over the pegs: every combination of the first `n` pegs is produced
+    /// before the `n + 1`-th peg is pulled. The expansions of successive templates are
+    /// interleaved.
+    BreadthFirst,
+    /// Cantor-style dovetailing, i.e. level order by the sum of the peg indices: tuples
+    /// are produced in order of the sum of their indices, and the expansions of
+    /// successive templates are interleaved.
+    Diagonal,
+}
+
+impl Traversal {
+    /// Whether every term appears at some finite position, even when the pegs or the
+    /// templates are infinite.
+    pub fn is_fair(self) -> bool {
+        matches!(self, Traversal::BreadthFirst | Traversal::Diagonal)
+    }
+}
+
+impl Display for Traversal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Traversal::DepthFirst => write!(f, "depth-first"),
+            Traversal::ReverseLexicographic => write!(f, "reverse-lex"),
+            Traversal::BreadthFirst => write!(f, "breadth-first"),
+            Traversal::Diagonal => write!(f, "diagonal"),
+        }
+    }
+}
+
+impl FromStr for Traversal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            Traversal::DepthFirst,
+            Traversal::ReverseLexicographic,
+            Traversal::BreadthFirst,
+            Traversal::Diagonal,
+        ]
+        .into_iter()
+        .find(|t| t.to_string() == s)
+        .ok_or_else(|| format!("unknown traversal `{s}`"))
+    }
+}
+
+/// How a [`Workload::Plug`] fills in the instances of its hole in a template.
+///
+/// [`Workload::Plug`]: crate::Workload::Plug
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Default)]
+pub enum PlugMode<A = String> {
+    /// Every instance of the hole is filled in with any of the pegs, independently of
+    /// the others, so `(+ ?A ?A)` plugged with `{0 1 2}` becomes 9 terms.
+    #[default]
+    Independent,
+    /// The hole is a metavariable that stands for the same peg everywhere, so
+    /// `(+ ?A ?A)` plugged with `{0 1 2}` becomes `(+ 0 0) (+ 1 1) (+ 2 2)`. The
+    /// [`Traversal`] then only decides how the templates and the pegs are interleaved.
+    Uniform,
+    /// Like `Independent`, but the operators in the list are commutative, so the
+    /// instances of the hole among the arguments of one of them are only filled in
+    /// with pegs in the order the pegs come in. With `+` commutative, `(+ ?A ?A)`
+    /// plugged with `{0 1 2}` becomes `(+ 0 0) (+ 0 1) (+ 0 2) (+ 1 1) (+ 1 2) (+ 2 2)`.
+    ///
+    /// Only the arguments themselves are reordered: nested applications of the same
+    /// operator aren't flattened, so associativity is not taken into account. Pegs
+    /// that are equal or differ only in order are not recognized either. Every
+    /// template is expanded in lexicographic order, so a commutative plug is never
+    /// fair, and `plug` in the workload language rejects the fair [`Traversal`]s for
+    /// it. Given one anyway, only the expansions of the templates are interleaved.
+    Commutative(Vec<A>),
+}
+
+impl<A: Display> Display for PlugMode<A> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlugMode::Independent => write!(f, "independent"),
+            PlugMode::Uniform => write!(f, "uniform"),
+            PlugMode::Commutative(ops) => {
+                write!(f, "commutative (")?;
+                for (i, op) in ops.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{op}")?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl FromStr for PlugMode {
+    type Err = String;
+
+    /// The names of the modes, where the operators of a commutative plug follow as a
+    /// list, as in `commutative (+ *)`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(ops) = s.strip_prefix("commutative") {
+            return match ops.parse::<Sexp>() {
+                Ok(Sexp::List(ops)) => ops
+                    .into_iter()
+                    .map(|op| match op {
+                        Sexp::Atom(op) => Ok(op),
+                        Sexp::Hole(_) | Sexp::List(_) => {
+                            Err(format!("operator `{op}` is not an atom"))
+                        }
+                    })
+                    .collect::<Result<_, _>>()
+                    .map(PlugMode::Commutative),
+                _ => Err("expected a list of commutative operators".to_string()),
+            };
+        }
+        [PlugMode::Independent, PlugMode::Uniform]
+            .into_iter()
+            .find(|m| m.to_string() == s)
+            .ok_or_else(|| format!("unknown plug mode `{s}`"))
+    }
+}
+
+/// The prefix of a peg iterator that has been pulled so far.
+struct PegCache<I: Iterator> {
+    iter: Option<I>,
+    pegs: Vec<I::Item>,
+}
+
+impl<I: Iterator> PegCache<I> {
+    fn new(iter: I) -> Self {
+        PegCache {
+            iter: Some(iter),
+            pegs: vec![],
+        }
+    }
+
+    /// Pull pegs until `n` are available or the iterator runs out, and return how
+    /// many are available.
+    fn available(&mut self, n: usize) -> usize {
+        while self.pegs.len() < n {
+            match self.iter.as_mut().and_then(Iterator::next) {
+                Some(peg) => self.pegs.push(peg),
+                None => {
+                    self.iter = None;
+                    break;
+                }
+            }
+        }
+        self.pegs.len().min(n)
+    }
+
+    fn exhausted(&self) -> bool {
+        self.iter.is_none()
+    }
+}
+
+/// How the fair traversals group tuples of peg indices into finite levels.
+#[derive(Clone, Copy)]
+pub(crate) enum Level {
+    /// Level `l` holds the tuples whose indices sum to `l`.
+    Sum,
+    /// Level `l` holds the tuples whose largest index is `l`.
+    Max,
+}
+
+impl Level {
+    /// The lexicographically smallest `k`-tuple on `level` with entries below `bound`,
+    /// if there is one.
+    fn first(self, level: usize, k: usize, bound: usize) -> Option<Vec<usize>> {
+        let mut tuple = vec![0; k];
+        match self {
+            Level::Sum => (fill_from_right(&mut tuple, level, bound) == 0).then_some(tuple),
+            Level::Max => (level < bound).then(|| {
+                tuple[k - 1] = level;
+                tuple
+            }),
+        }
+    }
+
+    /// Step `tuple` to the next tuple on `level` in lexicographic order, with entries
+    /// below `bound`. Returns `false` if it was the last one.
+    fn next(self, tuple: &mut [usize], level: usize, bound: usize) -> bool {
+        match self {
+            Level::Sum => {
+                let mut suffix = 0;
+                for j in (0..tuple.len().saturating_sub(1)).rev() {
+                    suffix += tuple[j + 1];
+                    if tuple[j] + 1 < bound && suffix > 0 {
+                        tuple[j] += 1;
+                        fill_from_right(&mut tuple[j + 1..], suffix - 1, bound);
+                        return true;
+                    }
+                }
+                false
+            }
+            Level::Max => {
+                // count up like an odometer whose digits go up to `level` ...
+                let Some(j) = tuple.iter().rposition(|&i| i < level) else {
+                    return false;
+                };
+                tuple[j] += 1;
+                tuple[j + 1..].fill(0);
+                // ... skipping straight to the next tuple that uses `level` at all
+                if !tuple.contains(&level) {
+                    *tuple.last_mut().unwrap() = level;
+                }
+                true
+            }
+        }
+    }
+
+    /// Whether `level` is the last level that isn't empty, given that there are
+    /// exactly `bound` pegs.
+    fn is_last(self, level: usize, k: usize, bound: usize) -> bool {
+        match self {
+            Level::Sum => level >= k * (bound - 1),
+            Level::Max => level + 1 >= bound,
+        }
+    }
+}
+
+/// Greedily spread `sum` over `tuple` from the right, with entries below `bound`.
+/// Returns what didn't fit.
+fn fill_from_right(tuple: &mut [usize], mut sum: usize, bound: usize) -> usize {
+    for slot in tuple.iter_mut().rev() {
+        *slot = sum.min(bound - 1);
+        sum -= *slot;
+    }
+    sum
+}
+
+/// Enumerates the ways of filling in every instance of `needle` in a template one
+/// level at a time: every tuple of peg indices on level `0`, then every tuple on level
+/// `1`, and so on, where a tuple's level is either the sum or the maximum of its
+/// indices. Pegs are pulled lazily, only once a level needs them, so this
+/// works for infinite peg iterators.
+pub struct SexpLevelIter<I, A = String>
+where
+    I: Iterator<Item = Sexp<A>>,
+{
+    template: Sexp<A>,
+    needle: A,
+    holes: usize,
+    pegs: PegCache<I>,
+    levels: Level,
+    level: usize,
+    current: Option<Vec<usize>>,
+    done: bool,
+}
+
+impl<I: Iterator<Item = Sexp<A>>, A: Atom> SexpLevelIter<I, A> {
+    pub(crate) fn new(template: Sexp<A>, needle: A, pegs: I, levels: Level) -> Self {
+        SexpLevelIter {
+            holes: template.occurrences(&needle),
+            template,
+            needle,
+            pegs: PegCache::new(pegs),
+            levels,
+            level: 0,
+            current: None,
+            done: false,
+        }
+    }
+}
+
+impl<I: Iterator<Item = Sexp<A>>, A: Atom> Iterator for SexpLevelIter<I, A> {
+    type Item = Sexp<A>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            // no tuple on a level can use an index larger than the level
+            let bound = self.pegs.available(self.level + 1);
+            if bound == 0 || self.holes == 0 {
+                // like the depth-first traversal, a template without the hole is
+                // produced once, provided that there is something to plug it with
+                self.done = true;
+                return (bound > 0).then(|| self.template.clone());
+            }
+
+            let next = match self.current.take() {
+                None => self.levels.first(self.level, self.holes, bound),
+                Some(mut tuple) => self
+                    .levels
+                    .next(&mut tuple, self.level, bound)
+                    .then_some(tuple),
+            };
+
+            match next {
+                Some(tuple) => {
+                    let term = self
+                        .template
+                        .fill(&self.needle, &mut tuple.iter().map(|&i| &self.pegs.pegs[i]));
+                    self.current = Some(tuple);
+                    return Some(term);
+                }
+                None => {
+                    self.done =
+                        self.pegs.exhausted() && self.levels.is_last(self.level, self.holes, bound);
+                    self.level += 1;
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Flattens an iterator of iterators fairly. Every round opens the next inner iterator
+/// and then takes one item from each open one, so that an infinite inner iterator can't
+/// starve the ones after it.
+pub(crate) struct Dovetail<I: Iterator> {
+    outer: Option<I>,
+    open: Vec<I::Item>,
+    cursor: usize,
+}
+
+impl<I: Iterator> Dovetail<I> {
+    pub(crate) fn new(outer: I) -> Self {
+        Dovetail {
+            outer: Some(outer),
+            open: vec![],
+            cursor: 0,
+        }
+    }
+}
+
+impl<I> Iterator for Dovetail<I>
+where
+    I: Iterator,
+    I::Item: Iterator,
+{
+    type Item = <I::Item as Iterator>::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.cursor >= self.open.len() {
+                // start a new round
+                self.cursor = 0;
+                match self.outer.as_mut().map(Iterator::next) {
+                    Some(Some(inner)) => self.open.push(inner),
+                    Some(None) => self.outer = None,
+                    None if self.open.is_empty() => return None,
+                    None => (),
+                }
+                if self.open.is_empty() {
+                    continue;
+                }
+            }
+
+            match self.open[self.cursor].next() {
+                Some(item) => {
+                    self.cursor += 1;
+                    return Some(item);
+                }
+                None => {
+                    self.open.remove(self.cursor);
+                }
+            }
+        }
+    }
+}
+
+/// Takes one item from each iterator in turn, skipping the ones that have run out.
+pub(crate) struct RoundRobin<I> {
+    iters: Vec<I>,
+    cursor: usize,
+}
+
+impl<I> RoundRobin<I> {
+    pub(crate) fn new(iters: Vec<I>) -> Self {
+        RoundRobin { iters, cursor: 0 }
+    }
+}
+
+impl<I: Iterator> Iterator for RoundRobin<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.iters.is_empty() {
+            self.cursor %= self.iters.len();
+            match self.iters[self.cursor].next() {
+                Some(item) => {
+                    self.cursor += 1;
+                    return Some(item);
+                }
+                None => {
+                    self.iters.remove(self.cursor);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use super::*;
+    use crate::Workload;
+
+    const TRAVERSALS: [Traversal; 4] = [
+        Traversal::DepthFirst,
+        Traversal::ReverseLexicographic,
+        Traversal::BreadthFirst,
+        Traversal::Diagonal,
+    ];
+
+    fn terms(src: &str) -> Vec<String> {
+        let wkld = Workload::parse(src).unwrap();
+        wkld.into_iter().map(|sexp| sexp.to_string()).collect()
+    }
+
+    #[test]
+    fn same_terms_as_depth_first() {
+        let templates = ["{(+ ?A ?A)}", "{x (f ?A (g ?A ?A)) (h ?B)}", "{x y}"];
+        let pegs = ["{0 1 2}", "{}", "{0}", "{(s ?B) 1}"];
+        for template in templates {
+            for pegs in pegs {
+                let src = |t: Traversal| format!("plug({template}, A, {pegs}, {t})");
+                let mut expected = terms(&src(Traversal::DepthFirst));
+                expected.sort();
+                for t in TRAVERSALS {
+                    let mut found = terms(&src(t));
+                    found.sort();
+                    assert_eq!(found, expected, "{}", src(t));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn orders() {
+        let expected: [(Traversal, &[&str]); 4] = [
+            (
+                Traversal::DepthFirst,
+                &[
+                    "0 0", "0 1", "0 2", "1 0", "1 1", "1 2", "2 0", "2 1", "2 2",
+                ],
+            ),
+            (
+                Traversal::ReverseLexicographic,
+                &[
+                    "0 0", "1 0", "2 0", "0 1", "1 1", "2 1", "0 2", "1 2", "2 2",
+                ],
+            ),
+            (
+                Traversal::BreadthFirst,
+                &[
+                    "0 0", "0 1", "1 0", "1 1", "0 2", "1 2", "2 0", "2 1", "2 2",
+                ],
+            ),
+            (
+                Traversal::Diagonal,
+                &[
+                    "0 0", "0 1", "1 0", "0 2", "1 1", "2 0", "1 2", "2 1", "2 2",
+                ],
+            ),
+        ];
+        for (t, order) in expected {
+            let order: Vec<String> = order.iter().map(|args| format!("(+ {args})")).collect();
+            assert_eq!(
+                terms(&format!("plug({{(+ ?A ?A)}}, A, {{0 1 2}}, {t})")),
+                order,
+                "{t}"
+            );
+        }
+    }
+
+    #[test]
+    fn pegs_are_pulled_lazily() {
+        // level `l` only needs the first `l + 1` pegs
+        let pulls: [(Level, &[usize]); 2] = [
+            (Level::Sum, &[1, 2, 2, 3, 3, 3, 4]),
+            (Level::Max, &[1, 2, 2, 2, 3, 3, 3, 3, 3, 4]),
+        ];
+        for (levels, pulls) in pulls {
+            let pulled = Rc::new(Cell::new(0));
+            let pegs = {
+                let pulled = pulled.clone();
+                (0..).map(move |i| {
+                    pulled.set(pulled.get() + 1);
+                    Sexp::Atom(i.to_string())
+                })
+            };
+            let template: Sexp = "(+ ?A ?A)".parse().unwrap();
+            let mut iter = SexpLevelIter::new(template, "A".to_string(), pegs, levels);
+            for (i, &n) in pulls.iter().enumerate() {
+                assert!(iter.next().is_some());
+                assert_eq!(pulled.get(), n, "{i}");
+            }
+        }
+    }
+
+    fn naturals() -> Workload {
+        Workload::stream(|| (0..).map(|i| Sexp::Atom(i.to_string())))
+    }
+
+    #[test]
+    fn diagonal_reaches_every_term_of_infinite_pegs() {
+        let templates = Workload::parse("{(+ ?A ?A)}").unwrap();
+        let wkld = templates.plug_with("A", naturals(), Traversal::Diagonal);
+        let target: Sexp = "(+ 1 0)".parse().unwrap();
+        assert_eq!(wkld.into_iter().position(|s| s == target), Some(2));
+    }
+
+    #[test]
+    fn dovetail_interleaves_infinite_templates() {
+        let iters = (0..).map(|i| (0..).map(move |j| (i, j)));
+        let first: Vec<(usize, usize)> = Dovetail::new(iters).take(6).collect();
+        assert_eq!(first, [(0, 0), (0, 1), (1, 0), (0, 2), (1, 1), (2, 0)]);
+
+        // every template of an infinite workload gets expanded
+        let templates = Workload::parse("{(f ?B ?A)}")
+            .unwrap()
+            .plug("B", naturals());
+        let wkld = templates.plug_with("A", naturals(), Traversal::Diagonal);
+        let target: Sexp = "(f 3 2)".parse().unwrap();
+        assert!(wkld.into_iter().take(100).any(|s| s == target));
+    }
+}
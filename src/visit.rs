@@ -0,0 +1,71 @@
+use std::{collections::HashSet, ops::ControlFlow};
+
+use crate::{workload::SharedPegs, Atom, Dedup, Sexp, SexpSubstIter, Workload};
+
+impl<A: Atom> Workload<A> {
+    /// Call `f` on every term, in the order of `into_iter`, until it breaks.
+    ///
+    /// Unlike `into_iter`, this lends the terms instead of handing them over, so terms
+    /// that are already stored somewhere aren't copied: the items of sets, and the
+    /// terms of depth-first plugs of a single independent hole, which are filled in in
+    /// a single buffer. Filters and appends pass the terms through as they are, and
+    /// everything else is enumerated with `into_iter`.
+    pub fn try_for_each_ref<B>(
+        self,
+        mut f: impl FnMut(&Sexp<A>) -> ControlFlow<B>,
+    ) -> ControlFlow<B> {
+        self.visit(&mut f)
+    }
+
+    /// Call `f` on every term, in the order of `into_iter`, lending the terms like
+    /// [`Workload::try_for_each_ref`].
+    pub fn for_each_ref(self, mut f: impl FnMut(&Sexp<A>)) {
+        let _ = self.visit::<()>(&mut |sexp| {
+            f(sexp);
+            ControlFlow::Continue(())
+        });
+    }
+
+    fn visit<B>(self, f: &mut dyn FnMut(&Sexp<A>) -> ControlFlow<B>) -> ControlFlow<B> {
+        let wkld = match self.into_lexicographic_plug() {
+            Ok((wkld, hole, pegs, from_right)) => {
+                let pegs = SharedPegs::new(pegs);
+                return wkld.visit(&mut |template| {
+                    let pegs = pegs.clone();
+                    let mut iter =
+                        SexpSubstIter::new(template.clone(), hole.clone(), move || pegs.iter());
+                    if from_right {
+                        iter = iter.rightmost_first();
+                    }
+                    while let Some(sexp) = iter.next_ref() {
+                        f(sexp)?;
+                    }
+                    ControlFlow::Continue(())
+                });
+            }
+            Err(wkld) => wkld,
+        };
+        match wkld {
+            Workload::Set(v) => v.iter().try_for_each(f),
+            Workload::Filter(wkld, filter) => wkld.visit(&mut |sexp| {
+                if filter.test(sexp) {
+                    f(sexp)?;
+                }
+                ControlFlow::Continue(())
+            }),
+            Workload::Dedup(wkld, Dedup::Exact) => {
+                // only the terms that are let through are copied
+                let mut seen = HashSet::new();
+                wkld.visit(&mut |sexp| {
+                    if !seen.contains(sexp) {
+                        seen.insert(sexp.clone());
+                        f(sexp)?;
+                    }
+                    ControlFlow::Continue(())
+                })
+            }
+            Workload::Append(wklds) => wklds.into_iter().try_for_each(|wkld| wkld.visit(f)),
+            wkld => wkld.into_iter().try_for_each(|sexp| f(&sexp)),
+        }
+    }
+}
@@ -0,0 +1,572 @@
+use std::{fmt::Debug, ops::Add, sync::Arc};
+
+use crate::{
+    traversal::{Dovetail, Level, RoundRobin, SexpLevelIter},
+    Atom, Dedup, Filter, Metric, PlugMode, Sexp, SexpPlugManyIter, SexpSubstIter, Traversal,
+};
+
+/// A workload whose terms are produced lazily by a function, e.g. an infinite one.
+/// The function is called again every time the workload is iterated.
+#[derive(Clone)]
+pub struct Stream<A = String>(Arc<dyn Fn() -> Box<dyn Iterator<Item = Sexp<A>>> + Send + Sync>);
+
+impl<A> PartialEq for Stream<A> {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl<A> Eq for Stream<A> {}
+
+impl<A> Debug for Stream<A> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Stream({:p})", Arc::as_ptr(&self.0))
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Workload<A = String> {
+    Set(Vec<Sexp<A>>),
+    Stream(Stream<A>),
+    Plug(Box<Self>, A, Box<Self>, Traversal, PlugMode<A>),
+    /// Every term of the workload with the instances of several holes filled in at
+    /// once, each with the terms of its own workload. See [`Workload::plug_many`].
+    PlugMany(Box<Self>, Vec<(A, Self)>),
+    Filter(Box<Self>, Filter<A>),
+    /// The terms of the workload, without the ones that are the same as an earlier
+    /// one. See [`Workload::dedup`].
+    Dedup(Box<Self>, Dedup<A>),
+    /// Every term of the first workload, then every term of the second, and so on.
+    Append(Vec<Self>),
+    /// One term of each workload in turn, so that an infinite workload doesn't starve
+    /// the ones after it.
+    Interleave(Vec<Self>),
+}
+
+impl<A: Atom> Workload<A> {
+    pub fn stream<F, I>(f: F) -> Self
+    where
+        F: Fn() -> I + Send + Sync + 'static,
+        I: Iterator<Item = Sexp<A>> + 'static,
+    {
+        Workload::Stream(Stream(Arc::new(move || Box::new(f()))))
+    }
+
+    pub fn plug(self, hole: impl Into<A>, pegs: Self) -> Self {
+        self.plug_with(hole, pegs, Traversal::default())
+    }
+
+    pub fn plug_with(self, hole: impl Into<A>, pegs: Self, traversal: Traversal) -> Self {
+        Workload::Plug(
+            Box::new(self),
+            hole.into(),
+            Box::new(pegs),
+            traversal,
+            PlugMode::Independent,
+        )
+    }
+
+    /// Plug the hole with one peg at a time, filling in all of its instances in a
+    /// template with that same peg. See [`PlugMode::Uniform`].
+    pub fn plug_uniform(self, hole: impl Into<A>, pegs: Self) -> Self {
+        Workload::Plug(
+            Box::new(self),
+            hole.into(),
+            Box::new(pegs),
+            Traversal::default(),
+            PlugMode::Uniform,
+        )
+    }
+
+    /// Plug the hole, treating the operators in `ops` as commutative, so that only one
+    /// ordering of the pegs among their arguments is produced. See
+    /// [`PlugMode::Commutative`].
+    pub fn plug_commutative(
+        self,
+        hole: impl Into<A>,
+        pegs: Self,
+        ops: &[impl Into<A> + Clone],
+    ) -> Self {
+        Workload::Plug(
+            Box::new(self),
+            hole.into(),
+            Box::new(pegs),
+            Traversal::default(),
+            PlugMode::Commutative(ops.iter().map(|op| op.clone().into()).collect()),
+        )
+    }
+
+    /// Plug several distinct holes in a single pass over each template, with one
+    /// combined traversal of all the ways of filling in their instances. This produces
+    /// the same terms as plugging the holes one after the other, but in a different
+    /// order: lexicographically by the instances of all the holes from left to right.
+    ///
+    /// The holes are filled in simultaneously, so unlike with chained plugs, holes that
+    /// appear in the pegs of another hole are left alone. If a hole is listed more than
+    /// once, the first entry is used.
+    pub fn plug_many(self, plugs: &[(impl Into<A> + Clone, Self)]) -> Self {
+        let mut holes: Vec<(A, Self)> = vec![];
+        for (hole, pegs) in plugs {
+            let hole = hole.clone().into();
+            if holes.iter().all(|(h, _)| *h != hole) {
+                holes.push((hole, pegs.clone()));
+            }
+        }
+        Workload::PlugMany(Box::new(self), holes)
+    }
+
+    pub fn filter(self, filter: Filter<A>) -> Self {
+        Workload::Filter(Box::new(self), filter)
+    }
+
+    /// Drop the terms that are the same as an earlier term, as decided by `dedup`. This
+    /// is lazy, but has to remember every term it lets through.
+    pub fn dedup(self, dedup: Dedup<A>) -> Self {
+        Workload::Dedup(Box::new(self), dedup)
+    }
+
+    pub fn append(self, other: Self) -> Self {
+        match self {
+            Workload::Append(mut wklds) => {
+                wklds.push(other);
+                Workload::Append(wklds)
+            }
+            wkld => Workload::Append(vec![wkld, other]),
+        }
+    }
+
+    pub fn interleave(wklds: impl IntoIterator<Item = Self>) -> Self {
+        Workload::Interleave(wklds.into_iter().collect())
+    }
+
+    /// Every term of a recursive grammar whose `metric` is at most `n`. The grammar is a
+    /// workload of productions for the nonterminal `start`, which is a hole; for
+    /// example, the grammar `EXPR := (+ EXPR EXPR) | (- EXPR) | VAR | CONST` is the set
+    ///
+    /// ```text
+    /// {(+ ?EXPR ?EXPR) (- ?EXPR) ?VAR ?CONST}
+    /// ```
+    ///
+    /// with `start` being `EXPR`. Other holes, like `?VAR` and `?CONST` above, are left
+    /// alone and can be plugged afterwards.
+    ///
+    /// This starts from the productions without `start`, and then repeatedly plugs the
+    /// terms so far into the productions, keeping those within the bound. Every round
+    /// derives terms that are one production deeper, so `n` rounds are enough to reach
+    /// the fixed point for [`Metric::Size`] and [`Metric::Depth`], and it stops early if
+    /// a round adds nothing new. The other metrics don't grow with every production, so
+    /// for them this only yields the terms that need at most `n` nested productions.
+    ///
+    /// The terms of every round but the last are plugged over and over again, so they
+    /// are collected into a [`Workload::Set`] up front. The last round, usually by far
+    /// the biggest, is enumerated lazily.
+    ///
+    /// Each term is produced exactly once, as long as the grammar is unambiguous (no
+    /// term can be derived in two different ways).
+    pub fn iter_metric(self, start: impl Into<A>, metric: Metric, n: usize) -> Self {
+        let start = start.into();
+        let bound = Filter::LessThan(metric, n.saturating_add(1));
+        let nonterminal = start.clone();
+        let leaves = self
+            .clone()
+            .filter(Filter::custom(move |sexp| {
+                sexp.occurrences(&nonterminal) == 0
+            }))
+            .filter(bound.clone());
+        if n <= 1 {
+            return leaves;
+        }
+
+        let mut pegs: Vec<Sexp<A>> = leaves.into_iter().collect();
+        for _ in 2..n {
+            let next: Vec<Sexp<A>> = self
+                .clone()
+                .plug(start.clone(), Workload::Set(pegs.clone()))
+                .filter(bound.clone())
+                .into_iter()
+                .collect();
+            // every round contains the previous one, so if it's no bigger, it's the same
+            if next.len() == pegs.len() {
+                return Workload::Set(next);
+            }
+            pegs = next;
+        }
+        self.plug(start, Workload::Set(pegs)).filter(bound)
+    }
+
+    /// The template workload, hole and pegs of a plug that fills in the instances of an
+    /// independent hole in lexicographic order, i.e. with the depth-first search of
+    /// [`SexpSubstIter`], along with whether it fills them in from the right. These are
+    /// the plugs whose position in the enumeration can be computed and recorded. Any
+    /// other workload is handed back as it is.
+    pub(crate) fn into_lexicographic_plug(self) -> Result<(Self, A, Self, bool), Self> {
+        match self {
+            Workload::Plug(
+                wkld,
+                hole,
+                pegs,
+                traversal @ (Traversal::DepthFirst | Traversal::ReverseLexicographic),
+                PlugMode::Independent,
+            ) => Ok((
+                *wkld,
+                hole,
+                *pegs,
+                traversal == Traversal::ReverseLexicographic,
+            )),
+            wkld => Err(wkld),
+        }
+    }
+
+    /// Like [`Workload::into_lexicographic_plug`], but borrows the parts of the plug.
+    pub(crate) fn as_lexicographic_plug(&self) -> Option<(&Self, &A, &Self, bool)> {
+        match self {
+            Workload::Plug(
+                wkld,
+                hole,
+                pegs,
+                traversal @ (Traversal::DepthFirst | Traversal::ReverseLexicographic),
+                PlugMode::Independent,
+            ) => Some((
+                wkld,
+                hole,
+                pegs,
+                *traversal == Traversal::ReverseLexicographic,
+            )),
+            _ => None,
+        }
+    }
+
+    /// The holes in the pegs of plugs that no enclosing plug fills in, in the order they
+    /// are found, without repetitions. Such a hole is left in the terms (or, for a peg
+    /// with the hole of its own depth-first plug, filled in over and over again), which
+    /// is usually a mistake, like writing `?A` in a peg set that was meant to hold the
+    /// atom `A`. Streams can't be looked into, so their holes aren't found.
+    pub fn unplugged_holes(&self) -> Vec<A> {
+        let mut found = vec![];
+        self.find_unplugged_holes(&mut vec![], false, &mut found);
+        found
+    }
+
+    /// Add the unplugged holes to `found`, where `plugged` are the holes of the
+    /// enclosing plugs whose templates this is part of, and `in_pegs` is whether this is
+    /// part of the pegs of a plug at all.
+    fn find_unplugged_holes<'a>(
+        &'a self,
+        plugged: &mut Vec<&'a A>,
+        in_pegs: bool,
+        found: &mut Vec<A>,
+    ) {
+        match self {
+            Workload::Set(v) if in_pegs => {
+                for hole in v.iter().flat_map(Sexp::holes) {
+                    if !plugged.contains(&hole) && !found.contains(hole) {
+                        found.push(hole.clone());
+                    }
+                }
+            }
+            Workload::Set(_) | Workload::Stream(_) => {}
+            Workload::Plug(wkld, hole, pegs, ..) => {
+                pegs.find_unplugged_holes(plugged, true, found);
+                plugged.push(hole);
+                wkld.find_unplugged_holes(plugged, in_pegs, found);
+                plugged.pop();
+            }
+            Workload::PlugMany(wkld, plugs) => {
+                for (_, pegs) in plugs {
+                    pegs.find_unplugged_holes(plugged, true, found);
+                }
+                let n = plugged.len();
+                plugged.extend(plugs.iter().map(|(hole, _)| hole));
+                wkld.find_unplugged_holes(plugged, in_pegs, found);
+                plugged.truncate(n);
+            }
+            Workload::Filter(wkld, _) | Workload::Dedup(wkld, _) => {
+                wkld.find_unplugged_holes(plugged, in_pegs, found)
+            }
+            Workload::Append(wklds) | Workload::Interleave(wklds) => wklds
+                .iter()
+                .for_each(|wkld| wkld.find_unplugged_holes(plugged, in_pegs, found)),
+        }
+    }
+}
+
+impl<A: Atom> Add for Workload<A> {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        self.append(rhs)
+    }
+}
+
+/// A cheap handle on the pegs of a [`Workload::Plug`], which the depth-first traversal
+/// iterates over again for every node of the substitution tree. Cloning a whole set
+/// every time would dominate the cost of enumeration, so the items of a set are only
+/// cloned one at a time, as they're needed.
+#[derive(Clone)]
+pub(crate) enum SharedPegs<A = String> {
+    Set(Arc<[Sexp<A>]>),
+    Workload(Arc<Workload<A>>),
+}
+
+impl<A: Atom> SharedPegs<A> {
+    pub(crate) fn new(pegs: Workload<A>) -> Self {
+        match pegs {
+            Workload::Set(v) => SharedPegs::Set(v.into()),
+            wkld => SharedPegs::Workload(Arc::new(wkld)),
+        }
+    }
+
+    pub(crate) fn iter(&self) -> Box<dyn Iterator<Item = Sexp<A>>> {
+        match self {
+            SharedPegs::Set(v) => {
+                let v = v.clone();
+                Box::new((0..v.len()).map(move |i| v[i].clone()))
+            }
+            SharedPegs::Workload(wkld) => (**wkld).clone().into_iter(),
+        }
+    }
+}
+
+impl<A: Atom> IntoIterator for Workload<A> {
+    type Item = Sexp<A>;
+    type IntoIter = Box<dyn Iterator<Item = Sexp<A>>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        let wkld = match self.into_lexicographic_plug() {
+            Ok((wkld, hole, pegs, from_right)) => {
+                let pegs = SharedPegs::new(pegs);
+                return Box::new(
+                    wkld.into_iter()
+                        .map(move |sexp| (sexp, hole.clone(), pegs.clone()))
+                        .flat_map(move |(sexp, hole, pegs)| {
+                            let iter = SexpSubstIter::new(sexp, hole, move || pegs.iter());
+                            if from_right {
+                                iter.rightmost_first()
+                            } else {
+                                iter
+                            }
+                        }),
+                );
+            }
+            Err(wkld) => wkld,
+        };
+        match wkld {
+            Workload::Set(v) => Box::new(v.into_iter()),
+            Workload::Stream(Stream(f)) => f(),
+            Workload::Plug(wkld, hole, pegs, traversal, PlugMode::Uniform) => {
+                let pegs = SharedPegs::new(*pegs);
+                let expand = move |sexp: Sexp<A>| -> Box<dyn Iterator<Item = Sexp<A>>> {
+                    if sexp.occurrences(&hole) == 0 {
+                        // produced once, as long as there is a peg
+                        Box::new(pegs.iter().take(1).map(move |_| sexp.clone()))
+                    } else {
+                        let hole = hole.clone();
+                        Box::new(
+                            pegs.iter()
+                                .map(move |peg| sexp.fill(&hole, &mut std::iter::repeat(&peg))),
+                        )
+                    }
+                };
+                if traversal.is_fair() {
+                    Box::new(Dovetail::new(wkld.into_iter().map(expand)))
+                } else {
+                    Box::new(wkld.into_iter().flat_map(expand))
+                }
+            }
+            Workload::Plug(wkld, hole, pegs, traversal, PlugMode::Commutative(ops)) => {
+                let pegs = SharedPegs::new(*pegs);
+                let expand = move |sexp: Sexp<A>| {
+                    let pegs = pegs.clone();
+                    SexpPlugManyIter::new(sexp, vec![hole.clone()], move |_| pegs.iter())
+                        .commutative(&ops)
+                };
+                if traversal.is_fair() {
+                    Box::new(Dovetail::new(wkld.into_iter().map(expand)))
+                } else {
+                    Box::new(wkld.into_iter().flat_map(expand))
+                }
+            }
+            // breadth-first or diagonal, the lexicographic ones were handled above
+            Workload::Plug(wkld, hole, pegs, traversal, PlugMode::Independent) => {
+                let levels = if traversal == Traversal::Diagonal {
+                    Level::Sum
+                } else {
+                    Level::Max
+                };
+                Box::new(Dovetail::new(wkld.into_iter().map(move |sexp| {
+                    SexpLevelIter::new(sexp, hole.clone(), pegs.clone().into_iter(), levels)
+                })))
+            }
+            Workload::PlugMany(wkld, plugs) => {
+                let (needles, pegs): (Vec<A>, Vec<SharedPegs<A>>) = plugs
+                    .into_iter()
+                    .map(|(hole, pegs)| (hole, SharedPegs::new(pegs)))
+                    .unzip();
+                let pegs: Arc<[SharedPegs<A>]> = pegs.into();
+                Box::new(wkld.into_iter().flat_map(move |sexp| {
+                    let pegs = pegs.clone();
+                    SexpPlugManyIter::new(sexp, needles.clone(), move |k| pegs[k].iter())
+                }))
+            }
+            Workload::Filter(wkld, filter) => {
+                Box::new(wkld.into_iter().filter(move |sexp| filter.test(sexp)))
+            }
+            Workload::Dedup(wkld, dedup) => dedup.apply(wkld.into_iter()),
+            Workload::Append(wklds) => Box::new(wklds.into_iter().flatten()),
+            Workload::Interleave(wklds) => Box::new(RoundRobin::new(
+                wklds.into_iter().map(Workload::into_iter).collect(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    const GRAMMAR: &str = "{(+ ?E ?E) (- ?E) x 1}";
+
+    /// The terms of [`GRAMMAR`] whose `metric` is at most `n`, by closing the leaves
+    /// under the productions until nothing changes.
+    fn brute_force(metric: Metric, n: usize) -> HashSet<Sexp> {
+        let atom = |a: &str| Sexp::Atom(a.to_string());
+        let mut terms: HashSet<Sexp> = HashSet::new();
+        loop {
+            let mut next: HashSet<Sexp> = [atom("x"), atom("1")].into();
+            for a in &terms {
+                next.insert(Sexp::List(vec![atom("-"), a.clone()]));
+                for b in &terms {
+                    next.insert(Sexp::List(vec![atom("+"), a.clone(), b.clone()]));
+                }
+            }
+            next.retain(|sexp| sexp.measure(metric) <= n);
+            if next == terms {
+                return terms;
+            }
+            terms = next;
+        }
+    }
+
+    #[test]
+    fn iter_metric_is_brute_force() {
+        let grammar = Workload::parse(GRAMMAR).unwrap();
+        for (metric, max) in [(Metric::Size, 9), (Metric::Depth, 3)] {
+            for n in 0..=max {
+                let terms: Vec<Sexp> = grammar
+                    .clone()
+                    .iter_metric("E", metric, n)
+                    .into_iter()
+                    .collect();
+                let distinct: HashSet<Sexp> = terms.iter().cloned().collect();
+                assert_eq!(distinct.len(), terms.len(), "{metric} {n}");
+                assert!(terms.iter().all(|sexp| sexp.measure(metric) <= n));
+                assert_eq!(distinct, brute_force(metric, n), "{metric} {n}");
+            }
+        }
+    }
+
+    #[test]
+    fn iter_metric_small_bounds() {
+        let grammar = Workload::parse(GRAMMAR).unwrap();
+        let leaves = |n| -> Vec<String> {
+            let wkld = grammar.clone().iter_metric("E", Metric::Size, n);
+            wkld.into_iter().map(|sexp| sexp.to_string()).collect()
+        };
+        assert!(leaves(0).is_empty());
+        assert_eq!(leaves(1), ["x", "1"]);
+    }
+
+    #[test]
+    fn iter_metric_stops_at_the_fixed_point() {
+        // no production fits in the bound, so the second round adds nothing
+        let grammar = Workload::parse("{x (f ?E ?E ?E ?E ?E ?E)}").unwrap();
+        let wkld = grammar.iter_metric("E", Metric::Size, 6);
+        assert_eq!(wkld, Workload::Set(vec![Sexp::Atom("x".to_string())]));
+    }
+
+    fn terms(src: &str) -> Vec<String> {
+        let wkld = Workload::parse(src).unwrap();
+        wkld.into_iter().map(|sexp| sexp.to_string()).collect()
+    }
+
+    #[test]
+    fn plug_many_is_chained_plugs() {
+        let templates = "{(f ?A ?B ?A) (g ?B) x (h ?C)}";
+        let mut many = terms(&format!("plug({templates}, A, {{0 1}}, B, {{2 (s 3)}})"));
+        let mut chained = terms(&format!(
+            "plug(plug({templates}, A, {{0 1}}), B, {{2 (s 3)}})"
+        ));
+        many.sort();
+        chained.sort();
+        assert_eq!(many, chained);
+    }
+
+    #[test]
+    fn plug_many_order() {
+        let src = "plug({(f ?A ?B) (g ?B ?A)}, A, {0 1}, B, {2 3})";
+        let expected = [
+            "(f 0 2)", "(f 0 3)", "(f 1 2)", "(f 1 3)", "(g 2 0)", "(g 2 1)", "(g 3 0)", "(g 3 1)",
+        ];
+        assert_eq!(terms(src), expected);
+        // the first entry for a hole wins
+        let wkld = Workload::parse("{(f ?A)}").unwrap().plug_many(&[
+            ("A", Workload::parse("{0}").unwrap()),
+            ("A", Workload::parse("{1}").unwrap()),
+        ]);
+        assert_eq!(
+            wkld.into_iter().collect::<Vec<_>>(),
+            ["(f 0)".parse().unwrap()]
+        );
+    }
+
+    #[test]
+    fn plug_many_without_pegs() {
+        // like a chained plug, a hole without pegs leaves nothing, whether the template
+        // contains it or not
+        assert!(terms("plug({(f ?A) x}, A, {0}, B, {})").is_empty());
+        assert!(terms("plug({(f ?A) x}, B, {}, A, {0})").is_empty());
+        assert!(terms("plug(plug({(f ?A) x}, A, {0}), B, {})").is_empty());
+    }
+
+    #[test]
+    fn uniform() {
+        let src = "plug({(+ ?A ?A) x}, A, {0 1 2}, uniform)";
+        assert_eq!(terms(src), ["(+ 0 0)", "(+ 1 1)", "(+ 2 2)", "x"]);
+        for src in [
+            "plug({(+ ?A ?A) x (f ?A (g ?A))}, A, {0 1 2}, uniform)",
+            "plug({(+ ?A ?A) x}, A, {}, uniform)",
+            "plug(plug({(+ ?A ?B ?B) (h ?B)}, A, {0 1}), B, {2 (s 3)}, uniform)",
+        ] {
+            for t in ["depth-first", "reverse-lex", "breadth-first", "diagonal"] {
+                let src = src.replace("uniform", &format!("{t}, uniform"));
+                let wkld = Workload::parse(&src).unwrap();
+                let count = wkld.count().unwrap();
+                assert_eq!(count, terms(&src).len() as u128, "{src}");
+            }
+        }
+    }
+
+    #[test]
+    fn unplugged_holes() {
+        let cases: [(&str, &[&str]); 8] = [
+            // holes of the templates are meant to be left alone
+            ("plug({(f ?A ?Z)}, A, {0})", &[]),
+            ("plug({(f ?A)}, A, {(g ?X ?Y ?X)})", &["X", "Y"]),
+            ("plug(plug({(f ?A ?B)}, A, {?B ?C}), B, {0})", &["C"]),
+            // the pegs only contain holes that the enclosing plug fills in
+            ("plug(plug({(f ?A)}, A, {(g ?B)}), B, {0})", &[]),
+            ("plug({(f ?A)}, A, plug({(g ?B)}, B, {?C 0}))", &["C"]),
+            // the holes of a plug_many are filled in at once, so not in each other's pegs
+            ("plug({(f ?A ?B)}, A, {?B}, B, {?D})", &["B", "D"]),
+            // a peg with the hole of its own plug
+            ("plug({(f ?A)}, A, {0 (g ?A)})", &["A"]),
+            ("plug(plug({(f ?A)}, A, {(g ?B)}), B, {0 (h ?B)})", &["B"]),
+        ];
+        for (src, holes) in cases {
+            let wkld = Workload::parse(src).unwrap();
+            assert_eq!(wkld.unplugged_holes(), holes, "{src}");
+        }
+    }
+}